@@ -1,7 +1,4 @@
--- Your SQL goes here
-
-PRAGMA encoding = 'UTF-8';
-PRAGMA foreign_keys = ON;
+-- The schema as it was before migrations were tracked
 
 CREATE TABLE tSong (
     key     INTEGER PRIMARY KEY NOT NULL CHECK (typeof(key) = 'integer'),
@@ -0,0 +1,19 @@
+-- Track when song data and analyses last changed, so the search index can be updated
+-- incrementally. Existing rows get 0, i.e. 'a long time ago'.
+ALTER TABLE tSongData ADD COLUMN tstamp BIGINT NOT NULL DEFAULT 0 CHECK (typeof(tstamp) = 'integer');
+ALTER TABLE tSongAnalysis ADD COLUMN tstamp BIGINT NOT NULL DEFAULT 0 CHECK (typeof(tstamp) = 'integer');
+
+-- Which songs have been pushed to which search index, and the time of the sync that pushed them
+CREATE TABLE tSearchIndexed (
+    index_name TEXT NOT NULL    CHECK (typeof(index_name) = 'text'),
+    key        INTEGER NOT NULL CHECK (typeof(key) = 'integer'),
+    tstamp     BIGINT NOT NULL  CHECK (typeof(tstamp) = 'integer'),
+    PRIMARY KEY (index_name, key),
+    FOREIGN KEY (key) REFERENCES tSong(key)
+);
+
+-- The search index that searches should go to - there's at most one row
+CREATE TABLE tSearchActiveIndex (
+    id         INTEGER PRIMARY KEY NOT NULL CHECK (id = 1),
+    index_name TEXT NOT NULL               CHECK (typeof(index_name) = 'text')
+);
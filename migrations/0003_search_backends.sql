@@ -0,0 +1,43 @@
+-- Each search backend has its own active index
+ALTER TABLE tSearchActiveIndex RENAME TO tSearchActiveIndexOld;
+CREATE TABLE tSearchActiveIndex (
+    backend    TEXT PRIMARY KEY NOT NULL CHECK (typeof(backend) = 'text'),
+    index_name TEXT NOT NULL             CHECK (typeof(index_name) = 'text')
+);
+INSERT INTO tSearchActiveIndex (backend, index_name) SELECT 'meili', index_name FROM tSearchActiveIndexOld;
+DROP TABLE tSearchActiveIndexOld;
+
+-- Documents in the embedded search backend, which needs no external service. The text columns
+-- are what gets searched, doc is the whole document as JSON.
+CREATE TABLE tSearchDoc (
+    id          INTEGER PRIMARY KEY NOT NULL,
+    index_name  TEXT NOT NULL CHECK (typeof(index_name) = 'text'),
+    key         TEXT NOT NULL CHECK (typeof(key) = 'text'),
+    name        TEXT NOT NULL CHECK (typeof(name) = 'text'),
+    sub_name    TEXT NOT NULL CHECK (typeof(sub_name) = 'text'),
+    description TEXT NOT NULL CHECK (typeof(description) = 'text'),
+    uploader    TEXT NOT NULL CHECK (typeof(uploader) = 'text'),
+    modes       TEXT NOT NULL CHECK (typeof(modes) = 'text'),
+    doc         BLOB NOT NULL CHECK (typeof(doc) = 'blob'),
+    UNIQUE (index_name, key)
+);
+
+-- https://www.sqlite.org/fts5.html#external_content_tables
+CREATE VIRTUAL TABLE tSearchFts USING fts5(
+    name, sub_name, description, uploader, modes,
+    content='tSearchDoc', content_rowid='id'
+);
+CREATE TRIGGER tSearchDocInsert AFTER INSERT ON tSearchDoc BEGIN
+    INSERT INTO tSearchFts (rowid, name, sub_name, description, uploader, modes)
+        VALUES (new.id, new.name, new.sub_name, new.description, new.uploader, new.modes);
+END;
+CREATE TRIGGER tSearchDocDelete AFTER DELETE ON tSearchDoc BEGIN
+    INSERT INTO tSearchFts (tSearchFts, rowid, name, sub_name, description, uploader, modes)
+        VALUES ('delete', old.id, old.name, old.sub_name, old.description, old.uploader, old.modes);
+END;
+CREATE TRIGGER tSearchDocUpdate AFTER UPDATE ON tSearchDoc BEGIN
+    INSERT INTO tSearchFts (tSearchFts, rowid, name, sub_name, description, uploader, modes)
+        VALUES ('delete', old.id, old.name, old.sub_name, old.description, old.uploader, old.modes);
+    INSERT INTO tSearchFts (rowid, name, sub_name, description, uploader, modes)
+        VALUES (new.id, new.name, new.sub_name, new.description, new.uploader, new.modes);
+END;
@@ -0,0 +1,19 @@
+-- Analysis results can now be for a single beatmap (one difficulty of one characteristic) as well
+-- as for the whole map, which has an empty characteristic and difficulty
+CREATE TABLE tSongAnalysisNew (
+    hash           TEXT NOT NULL             CHECK (typeof(hash) = 'text'),
+    analysis_name  TEXT NOT NULL             CHECK (typeof(analysis_name) = 'text'),
+    characteristic TEXT NOT NULL DEFAULT ''  CHECK (typeof(characteristic) = 'text'),
+    difficulty     TEXT NOT NULL DEFAULT ''  CHECK (typeof(difficulty) = 'text'),
+    result         BLOB NOT NULL             CHECK (typeof(result) = 'blob'),
+    tstamp         BIGINT NOT NULL DEFAULT 0 CHECK (typeof(tstamp) = 'integer'),
+    PRIMARY KEY (hash, analysis_name, characteristic, difficulty),
+    FOREIGN KEY (hash) REFERENCES tSongData(hash)
+);
+-- The bundled plugins now analyse each beatmap and output differently shaped results, so drop
+-- what they used to output - they'll be rerun by the next analyse
+INSERT INTO tSongAnalysisNew (hash, analysis_name, result, tstamp)
+    SELECT hash, analysis_name, result, tstamp FROM tSongAnalysis
+    WHERE analysis_name NOT IN ('parity', 'difficulty');
+DROP TABLE tSongAnalysis;
+ALTER TABLE tSongAnalysisNew RENAME TO tSongAnalysis;
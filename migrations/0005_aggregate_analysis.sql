@@ -0,0 +1,10 @@
+-- Results of aggregate analyses, which look across many songs at once. Keyed by song key rather
+-- than hash, since they're about a song's place among the others rather than its content.
+CREATE TABLE tSongAggregate (
+    key           INTEGER NOT NULL CHECK (typeof(key) = 'integer'),
+    analysis_name TEXT NOT NULL    CHECK (typeof(analysis_name) = 'text'),
+    result        BLOB NOT NULL    CHECK (typeof(result) = 'blob'),
+    tstamp        BIGINT NOT NULL  CHECK (typeof(tstamp) = 'integer'),
+    PRIMARY KEY (key, analysis_name),
+    FOREIGN KEY (key) REFERENCES tSong(key)
+);
@@ -0,0 +1,25 @@
+-- Fingerprints of each map's audio, charts and cover, for finding re-uploads and copied maps.
+-- version is the fingerprinting algorithm that made them, so they can be redone when it changes.
+CREATE TABLE tSongFingerprint (
+    hash        TEXT PRIMARY KEY NOT NULL CHECK (typeof(hash) = 'text'),
+    version     INTEGER NOT NULL          CHECK (typeof(version) = 'integer'),
+    fingerprint BLOB NOT NULL             CHECK (typeof(fingerprint) = 'blob'),
+    tstamp      BIGINT NOT NULL           CHECK (typeof(tstamp) = 'integer'),
+    FOREIGN KEY (hash) REFERENCES tSongData(hash)
+);
+
+-- Pairs of songs that look like duplicates of each other, from the last duplicates run. Scores
+-- are null if either song lacks that fingerprint or the pair wasn't similar enough to compare.
+CREATE TABLE tDuplicatePair (
+    key_a       INTEGER NOT NULL CHECK (typeof(key_a) = 'integer'),
+    key_b       INTEGER NOT NULL CHECK (typeof(key_b) = 'integer'),
+    audio_exact BOOLEAN NOT NULL CHECK (typeof(audio_exact) = 'integer' AND (audio_exact = 0 OR audio_exact = 1)),
+    audio       REAL             CHECK (audio IS NULL OR typeof(audio) = 'real'),
+    chart       REAL             CHECK (chart IS NULL OR typeof(chart) = 'real'),
+    cover       INTEGER          CHECK (cover IS NULL OR typeof(cover) = 'integer'),
+    PRIMARY KEY (key_a, key_b),
+    CHECK (key_a < key_b),
+    FOREIGN KEY (key_a) REFERENCES tSong(key),
+    FOREIGN KEY (key_b) REFERENCES tSong(key)
+);
+CREATE INDEX iDuplicatePair1 ON tDuplicatePair(key_b);
@@ -0,0 +1,11 @@
+-- What each analysis logged the last time it ran on a song, and why it failed if it did. Kept
+-- separately from the results since failed runs have no results but their logs matter most.
+CREATE TABLE tSongAnalysisLog (
+    hash          TEXT NOT NULL   CHECK (typeof(hash) = 'text'),
+    analysis_name TEXT NOT NULL   CHECK (typeof(analysis_name) = 'text'),
+    log           TEXT NOT NULL   CHECK (typeof(log) = 'text'),
+    error         TEXT            CHECK (error IS NULL OR typeof(error) = 'text'),
+    tstamp        BIGINT NOT NULL CHECK (typeof(tstamp) = 'integer'),
+    PRIMARY KEY (hash, analysis_name),
+    FOREIGN KEY (hash) REFERENCES tSongData(hash)
+);
@@ -0,0 +1,4 @@
+-- Which version of a registered plugin each result came from, so results from a version that was
+-- replaced while `analyse` was running count as missing and get redone. Built plugins have no
+-- version, which is 0.
+ALTER TABLE tSongAnalysis ADD COLUMN plugin_version BIGINT NOT NULL DEFAULT 0 CHECK (typeof(plugin_version) = 'integer');
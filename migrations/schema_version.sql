@@ -0,0 +1,7 @@
+-- Tracks which migrations in this directory have been applied, see src/migrations.rs
+
+CREATE TABLE IF NOT EXISTS schema_version (
+    version    INTEGER PRIMARY KEY NOT NULL CHECK (typeof(version) = 'integer'),
+    name       TEXT NOT NULL                CHECK (typeof(name) = 'text'),
+    applied_at BIGINT NOT NULL              CHECK (typeof(applied_at) = 'integer')
+);
@@ -0,0 +1,51 @@
+// Glue for the bsmeta host import module, shared by the interpreters
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+
+// Results of bsmeta_host_get other than lengths, see HostApi in wasm.rs
+#define BSMETA_NOT_FOUND -1
+#define BSMETA_BAD_ARGS -2
+#define BSMETA_FAILED -3
+// Not from the host
+#define BSMETA_NO_MEMORY -4
+#define BSMETA_CHANGED -5
+
+__attribute__((import_module("bsmeta"), import_name("get")))
+int64_t bsmeta_host_get(const char *name, size_t name_len, uint8_t *buf, size_t buf_len);
+
+static const char *bsmeta_strerror(int64_t err) {
+    switch (err) {
+    case BSMETA_NOT_FOUND: return "not found";
+    case BSMETA_BAD_ARGS: return "bad arguments";
+    case BSMETA_FAILED: return "failed to load (see stderr)";
+    case BSMETA_NO_MEMORY: return "out of memory";
+    case BSMETA_CHANGED: return "changed while reading";
+    default: return "unknown error";
+    }
+}
+
+// Fetch the JSON for a named resource, returning a malloced buffer (NUL terminated, for the JSON
+// parsers) with its length in *len - or NULL with the error in *len
+static char *bsmeta_fetch(const char *name, int64_t *len) {
+    size_t name_len = strlen(name);
+    int64_t needed = bsmeta_host_get(name, name_len, NULL, 0);
+    if (needed < 0) {
+        *len = needed;
+        return NULL;
+    }
+    char *buf = malloc(needed + 1);
+    if (!buf) {
+        *len = BSMETA_NO_MEMORY;
+        return NULL;
+    }
+    int64_t got = bsmeta_host_get(name, name_len, (uint8_t *)buf, needed);
+    if (got != needed) {
+        free(buf);
+        *len = got < 0 ? got : BSMETA_CHANGED;
+        return NULL;
+    }
+    buf[needed] = '\0';
+    *len = needed;
+    return buf;
+}
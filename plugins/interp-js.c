@@ -2,6 +2,7 @@
 #include <string.h>
 #include "quickjs/quickjs-libc.h"
 #include "quickjs/quickjs.h"
+#include "bsmeta.h"
 
 // Stolen from qjs.c
 static int eval_buf(JSContext *ctx, const void *buf, int buf_len,
@@ -32,6 +33,44 @@ static int eval_buf(JSContext *ctx, const void *buf, int buf_len,
     return ret;
 }
 
+// bsmeta.get(name) - the parsed resource, or null if there's no such resource
+static JSValue js_bsmeta_get(JSContext *ctx, JSValueConst this_val, int argc, JSValueConst *argv) {
+    const char *name = JS_ToCString(ctx, argv[0]);
+    if (!name) {
+        return JS_EXCEPTION;
+    }
+    int64_t len;
+    char *buf = bsmeta_fetch(name, &len);
+    JSValue ret;
+    if (buf) {
+        ret = JS_ParseJSON(ctx, buf, len, name);
+        free(buf);
+    } else if (len == BSMETA_NOT_FOUND) {
+        ret = JS_NULL;
+    } else {
+        ret = JS_ThrowInternalError(ctx, "bsmeta.get(%s) failed: %s", name, bsmeta_strerror(len));
+    }
+    JS_FreeCString(ctx, name);
+    return ret;
+}
+
+static const JSCFunctionListEntry js_bsmeta_funcs[] = {
+    JS_CFUNC_DEF("get", 1, js_bsmeta_get),
+};
+
+static int js_bsmeta_init(JSContext *ctx, JSModuleDef *m) {
+    return JS_SetModuleExportList(ctx, m, js_bsmeta_funcs, sizeof(js_bsmeta_funcs) / sizeof(js_bsmeta_funcs[0]));
+}
+
+static JSModuleDef *js_init_module_bsmeta(JSContext *ctx, const char *module_name) {
+    JSModuleDef *m = JS_NewCModule(ctx, module_name, js_bsmeta_init);
+    if (!m) {
+        return NULL;
+    }
+    JS_AddModuleExportList(ctx, m, js_bsmeta_funcs, sizeof(js_bsmeta_funcs) / sizeof(js_bsmeta_funcs[0]));
+    return m;
+}
+
 int run_script() {
     JSRuntime *rt;
     JSContext *ctx;
@@ -49,6 +88,7 @@ int run_script() {
     }
     js_init_module_std(ctx, "std");
     js_init_module_os(ctx, "os");
+    js_init_module_bsmeta(ctx, "bsmeta");
     js_std_add_helpers(ctx, -1, NULL);
 
     int ret;
@@ -56,8 +96,10 @@ int run_script() {
     const char *base =
         "import * as std from 'std';\n"
         "import * as os from 'os';\n"
+        "import * as bsmeta from 'bsmeta';\n"
         "globalThis.std = std;\n"
-        "globalThis.os = os;\n";
+        "globalThis.os = os;\n"
+        "globalThis.bsmeta = bsmeta;\n";
 
     ret = eval_buf(ctx, base, strlen(base), "<input>", JS_EVAL_TYPE_MODULE);
     if (ret != 0) { return ret; }
@@ -81,7 +123,8 @@ int run_script() {
 int main(int argc, char **argv) {
     setbuf(stdout, NULL);
     setbuf(stderr, NULL);
-    return run_script();
-    // TODO, or figure how to get return val from main
-    //exit(run_script());
+    // wasi-libc passes a non-zero return from main to proc_exit, making it the plugin's exit code.
+    // Uncaught exceptions come back as -1, so report them like the native interpreter would.
+    int ret = run_script();
+    return ret < 0 ? 1 : ret;
 }
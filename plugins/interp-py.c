@@ -1,7 +1,9 @@
 #include <stdint.h>
 #include <string.h>
 //#include "cpython/lib.zip.h"
+#define PY_SSIZE_T_CLEAN
 #include "cpython/Include/Python.h"
+#include "bsmeta.h"
 
 char *ttyname(int fd) { abort(); }
 int system(const char *command) { abort(); }
@@ -15,6 +17,44 @@ int kill(pid_t pid, int sig) { abort(); }
 pid_t wait(int *wstatus) { abort(); }
 int pipe(int pipefd[2]) { abort(); }
 
+// bsmeta.get(name) - the parsed resource, or None if there's no such resource
+static PyObject *bsmeta_get(PyObject *self, PyObject *args) {
+    const char *name;
+    if (!PyArg_ParseTuple(args, "s", &name)) {
+        return NULL;
+    }
+    int64_t len;
+    char *buf = bsmeta_fetch(name, &len);
+    if (!buf) {
+        if (len == BSMETA_NOT_FOUND) {
+            Py_RETURN_NONE;
+        }
+        return PyErr_Format(PyExc_RuntimeError, "bsmeta.get(%s) failed: %s", name, bsmeta_strerror(len));
+    }
+    PyObject *json = PyImport_ImportModule("json");
+    if (!json) {
+        free(buf);
+        return NULL;
+    }
+    PyObject *ret = PyObject_CallMethod(json, "loads", "s#", buf, (Py_ssize_t)len);
+    Py_DECREF(json);
+    free(buf);
+    return ret;
+}
+
+static PyMethodDef bsmeta_methods[] = {
+    {"get", bsmeta_get, METH_VARARGS, "Get a resource about the map being analysed from bsmeta"},
+    {NULL, NULL, 0, NULL},
+};
+
+static struct PyModuleDef bsmeta_module = {
+    PyModuleDef_HEAD_INIT, "bsmeta", NULL, -1, bsmeta_methods,
+};
+
+static PyObject *PyInit_bsmeta(void) {
+    return PyModule_Create(&bsmeta_module);
+}
+
 int run_script() {
     int ret;
 
@@ -41,6 +81,12 @@ int run_script() {
     Py_DontWriteBytecodeFlag = 1;
     Py_UnbufferedStdioFlag = 1;
 
+    ret = PyImport_AppendInittab("bsmeta", PyInit_bsmeta);
+    if (ret != 0) {
+        fprintf(stderr, "failed to add bsmeta module\n");
+        return ret;
+    }
+
     Py_InitializeEx(0); // don't initialize signals
 
     const char *filename = "/work/script.py";
@@ -59,7 +105,8 @@ int run_script() {
 int main(int argc, char **argv) {
     setbuf(stdout, NULL);
     setbuf(stderr, NULL);
-    return run_script();
-    // TODO, or figure how to get return val from main
-    //exit(run_script());
+    // wasi-libc passes a non-zero return from main to proc_exit, making it the plugin's exit code.
+    // Uncaught exceptions come back as -1, so report them like the native interpreter would.
+    int ret = run_script();
+    return ret < 0 ? 1 : ret;
 }
@@ -20,16 +20,6 @@ globalThis.os = os;
 
 function noop() {}
 
-// Override console.log to use stderr - stdout is how we're going to give output to bsmeta
-var oldLog = console.log;
-console.log = function () {
-    var strings = [];
-    for (var i = 0; i < arguments.length; i++) {
-        strings.push(arguments[i].toString());
-    }
-    std.err.puts('stdout:' + strings.join(' ') + '\n');
-}
-
 function getScrollLineHeight() {
     const el = document.createElement('div');
     el.style.fontSize = 'initial';
@@ -59,7 +49,24 @@ globalThis.window = {
 
 std.loadScript("/work/bs-parity-main.js");
 
-function analyseMap(mappath) {
+// bs-parity understands v2 beatmaps, so convert back from what bsmeta gives us - which means v3
+// maps work too
+function toV2(beatmap) {
+    let notes = beatmap.notes.map((n) => ({
+        _time: n.time, _lineIndex: n.line_index, _lineLayer: n.line_layer, _type: n.color, _cutDirection: n.cut_direction,
+    }));
+    let bombs = beatmap.bombs.map((b) => ({
+        _time: b.time, _lineIndex: b.line_index, _lineLayer: b.line_layer, _type: 3, _cutDirection: 0,
+    }));
+    return {
+        _notes: notes.concat(bombs).sort((a, b) => a._time - b._time),
+        _obstacles: beatmap.obstacles.map((o) => ({
+            _time: o.time, _lineIndex: o.line_index, _type: o.line_layer >= 2 ? 1 : 0, _duration: o.duration, _width: o.width,
+        })),
+    };
+}
+
+function analyseBeatmap(beatmap) {
     // Post-load overrides
     var TOTAL_OUTPUT = [];
     outputUI = function (note, parity, message, messageType, persistent = false) {
@@ -68,7 +75,7 @@ function analyseMap(mappath) {
     clearOutput = noop;
 
     // Prep globals
-    let parsed = JSON.parse(std.loadFile(mappath));
+    let parsed = toV2(beatmap);
     notesArray = getNotes(parsed);
     wallsArray = getWalls(parsed);
     ready = true;
@@ -83,28 +90,21 @@ function analyseMap(mappath) {
     };
 }
 
-let infodat = JSON.parse(std.loadFile("/data/info.dat"));
-let failed = [];
-infodat._difficultyBeatmapSets.forEach((diffSet) => {
-    let setName = diffSet._beatmapCharacteristicName;
-    diffSet._difficultyBeatmaps.forEach((diff) => {
-        let d = diff._difficulty;
-        if (d === "Easy" || d === "Normal") {
-            return;
-        }
-        if (d !== "Hard" && d !== "Expert" && d !== "ExpertPlus") {
-            throw 'Unknown difficulty';
-        }
-        let res = analyseMap('/data/' + diff._beatmapFilename);
-        if (res.num_errors > 0) {
-            failed.push(setName + ':' + d + ' had ' + res.num_errors + ' errors');
-        }
-        if (res.num_warnings > 10) {
-            failed.push(setName + ':' + d + ' had ' + res.num_warnings + ' warnings');
-        }
-    });
-});
-std.out.puts(JSON.stringify({
-    failed: failed.length !== 0,
-    whyfailed: failed.join(', '),
+// We're run once per beatmap
+let beatmapInfo = bsmeta.get("beatmap");
+let d = beatmapInfo.difficulty;
+if (d !== "Easy" && d !== "Normal" && d !== "Hard" && d !== "Expert" && d !== "ExpertPlus") {
+    throw 'Unknown difficulty';
+}
+let res = analyseBeatmap(bsmeta.get("beatmap/" + beatmapInfo.characteristic + "/" + d));
+let failed = false;
+if (d !== "Easy" && d !== "Normal") {
+    failed = res.num_errors > 0 || res.num_warnings > 10;
+}
+let out = std.open('/out/result.json', 'w');
+out.puts(JSON.stringify({
+    num_errors: res.num_errors,
+    num_warnings: res.num_warnings,
+    failed: failed,
 }));
+out.close();
@@ -0,0 +1,33 @@
+# An aggregate plugin - ranks each song against every other song
+
+import bisect
+import json
+
+songs = [json.loads(line) for line in open('/data/songs.jsonl')]
+
+def percentiles(values):
+    # Percentage of songs with a lower value, for each song with a value
+    known = sorted(v for v in values.values() if v is not None)
+    ranks = {}
+    for key, v in values.items():
+        if v is None or len(known) < 2:
+            ranks[key] = None
+            continue
+        below = bisect.bisect_left(known, v)
+        ranks[key] = round(100 * below / (len(known) - 1), 2)
+    return ranks
+
+durations = {s['key']: (s['extra'] or {}).get('song_duration') for s in songs}
+upvotes = {s['key']: s['upvotes'] for s in songs}
+duration_pcts = percentiles(durations)
+upvote_pcts = percentiles(upvotes)
+
+with open('/out/result.json', 'w') as f:
+    json.dump({
+        s['key']: {
+            'duration_percentile': duration_pcts[s['key']],
+            'upvotes_percentile': upvote_pcts[s['key']],
+            'top_5pct_upvotes': upvote_pcts[s['key']] is not None and upvote_pcts[s['key']] >= 95,
+        }
+        for s in songs
+    }, f)
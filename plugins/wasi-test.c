@@ -0,0 +1,310 @@
+// Exercises the parts of WASI that wasi-libc relies on for filesystem access, against the virtual
+// filesystem bsmeta gives plugins. Run with `bsmeta test-wasi`, which sets up the files below
+// /work that this expects:
+//   a.txt             "hello, world\n"
+//   sub/b.txt         "bee"
+//   sub/deeper/c.txt  "sea"
+//   many/...          200 empty files
+// and that /tmp starts off empty and writable.
+// Prints "ok <check>" or "FAIL <check>: <why>" for each check, then "done", and outputs {} to
+// /out/result.json.
+
+#include <dirent.h>
+#include <errno.h>
+#include <fcntl.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <time.h>
+#include <unistd.h>
+#include <wasi/api.h>
+
+#define CHECK(name, cond, ...) do { \
+        if (cond) { \
+            printf("ok %s\n", name); \
+        } else { \
+            printf("FAIL %s: ", name); \
+            printf(__VA_ARGS__); \
+            printf("\n"); \
+        } \
+    } while (0)
+
+// Checks that a call failed with the expected errno
+#define CHECK_ERRNO(name, ret, expected) do { \
+        int ret_ = (ret); \
+        int errno_ = errno; \
+        CHECK(name, ret_ == -1 && errno_ == (expected), \
+            "expected errno %d (%s), got ret %d errno %d (%s)", \
+            expected, strerror(expected), ret_, errno_, strerror(errno_)); \
+    } while (0)
+
+static int read_all(const char *path, char *buf, size_t len) {
+    int fd = open(path, O_RDONLY);
+    if (fd < 0) {
+        return -1;
+    }
+    ssize_t n = read(fd, buf, len - 1);
+    close(fd);
+    if (n < 0) {
+        return -1;
+    }
+    buf[n] = '\0';
+    return n;
+}
+
+static void check_readdir(void) {
+    DIR *dir = opendir("/work");
+    CHECK("opendir", dir != NULL, "errno %d (%s)", errno, strerror(errno));
+    if (dir == NULL) {
+        return;
+    }
+    int count = 0, dot = 0, dotdot = 0, file = 0, subdir = 0;
+    struct dirent *ent;
+    while ((ent = readdir(dir)) != NULL) {
+        count++;
+        if (strcmp(ent->d_name, ".") == 0) {
+            dot = ent->d_type == DT_DIR;
+        } else if (strcmp(ent->d_name, "..") == 0) {
+            dotdot = ent->d_type == DT_DIR;
+        } else if (strcmp(ent->d_name, "a.txt") == 0) {
+            file = ent->d_type == DT_REG;
+        } else if (strcmp(ent->d_name, "sub") == 0) {
+            subdir = ent->d_type == DT_DIR;
+        }
+    }
+    closedir(dir);
+    // a.txt, sub, many, plus . and ..
+    CHECK("readdir-count", count == 5, "got %d entries", count);
+    CHECK("readdir-dots", dot && dotdot, "missing or mistyped . or ..");
+    CHECK("readdir-types", file && subdir, "missing or mistyped a.txt or sub");
+
+    // Too many entries for wasi-libc's initial buffer, so it has to carry on from a cookie
+    dir = opendir("/work/many");
+    if (dir == NULL) {
+        CHECK("readdir-many", 0, "opendir errno %d (%s)", errno, strerror(errno));
+        return;
+    }
+    char seen[200] = {0};
+    int dups = 0;
+    count = 0;
+    while ((ent = readdir(dir)) != NULL) {
+        int i;
+        if (sscanf(ent->d_name, "file-with-a-fairly-long-name-%d", &i) == 1 && i >= 0 && i < 200) {
+            dups += seen[i];
+            seen[i] = 1;
+            count++;
+        }
+    }
+    CHECK("readdir-many", count == 200 && dups == 0, "got %d files, %d duplicates", count, dups);
+
+    rewinddir(dir);
+    count = 0;
+    long pos = -1;
+    char after[256] = "";
+    while ((ent = readdir(dir)) != NULL) {
+        count++;
+        if (count == 10) {
+            pos = telldir(dir);
+        } else if (count == 11) {
+            strcpy(after, ent->d_name);
+        }
+    }
+    CHECK("rewinddir", count == 202, "got %d entries after rewinding", count);
+    seekdir(dir, pos);
+    ent = readdir(dir);
+    CHECK("seekdir", ent != NULL && strcmp(ent->d_name, after) == 0,
+        "expected %s, got %s", after, ent ? ent->d_name : "nothing");
+    closedir(dir);
+
+    dir = opendir("/data");
+    int found = 0;
+    while (dir != NULL && (ent = readdir(dir)) != NULL) {
+        found |= strcmp(ent->d_name, "info.dat") == 0;
+    }
+    if (dir != NULL) {
+        closedir(dir);
+    }
+    CHECK("readdir-data", found, "no info.dat in /data");
+}
+
+static void check_paths(void) {
+    char buf[64];
+    int n = read_all("/work/sub/deeper/c.txt", buf, sizeof(buf));
+    CHECK("nested", n == 3 && strcmp(buf, "sea") == 0, "read %d", n);
+    n = read_all("/work/sub/deeper/../../a.txt", buf, sizeof(buf));
+    CHECK("dotdot", n == 13 && strcmp(buf, "hello, world\n") == 0, "read %d", n);
+    n = read_all("/work/./sub/./b.txt", buf, sizeof(buf));
+    CHECK("dot", n == 3 && strcmp(buf, "bee") == 0, "read %d", n);
+
+    struct stat st;
+    CHECK("stat-dir", stat("/work/sub/deeper", &st) == 0 && S_ISDIR(st.st_mode), "errno %d", errno);
+    CHECK("stat-file", stat("/work/sub/b.txt", &st) == 0 && S_ISREG(st.st_mode) && st.st_size == 3,
+        "errno %d", errno);
+
+    CHECK_ERRNO("escape", open("/work/../data/info.dat", O_RDONLY), ENOTCAPABLE);
+    CHECK_ERRNO("missing", open("/work/missing", O_RDONLY), ENOENT);
+    CHECK_ERRNO("file-as-dir", open("/work/a.txt/b", O_RDONLY), ENOTDIR);
+    CHECK_ERRNO("o-directory", open("/work/a.txt", O_RDONLY | O_DIRECTORY), ENOTDIR);
+    CHECK_ERRNO("o-creat", open("/work/new.txt", O_WRONLY | O_CREAT, 0644), EACCES);
+    CHECK_ERRNO("o-excl", open("/work/a.txt", O_RDONLY | O_CREAT | O_EXCL, 0644), EEXIST);
+    CHECK_ERRNO("o-wronly", open("/work/a.txt", O_WRONLY), EACCES);
+    CHECK_ERRNO("o-wronly-dir", open("/work/sub", O_WRONLY), EISDIR);
+    CHECK_ERRNO("o-trunc", open("/work/a.txt", O_RDONLY | O_TRUNC), EACCES);
+
+    CHECK_ERRNO("readlink", readlink("/work/a.txt", buf, sizeof(buf)), EINVAL);
+    CHECK_ERRNO("readlink-missing", readlink("/work/missing", buf, sizeof(buf)), ENOENT);
+}
+
+static void check_fds(void) {
+    char buf[64];
+    int fd = open("/work/a.txt", O_RDONLY);
+    if (fd < 0) {
+        CHECK("open", 0, "errno %d (%s)", errno, strerror(errno));
+        return;
+    }
+    ssize_t n = pread(fd, buf, 5, 7);
+    CHECK("pread", n == 5 && memcmp(buf, "world", 5) == 0, "read %zd", n);
+    n = read(fd, buf, 5);
+    CHECK("pread-keeps-position", n == 5 && memcmp(buf, "hello", 5) == 0, "read %zd", n);
+    n = pread(fd, buf, sizeof(buf), 100);
+    CHECK("pread-eof", n == 0, "read %zd", n);
+
+    CHECK("setfl", fcntl(fd, F_SETFL, O_NONBLOCK) == 0, "errno %d (%s)", errno, strerror(errno));
+    int flags = fcntl(fd, F_GETFL);
+    CHECK("getfl", flags != -1 && (flags & O_NONBLOCK), "flags %x", flags);
+
+    int dirfd = open("/work/sub", O_RDONLY | O_DIRECTORY);
+    CHECK("open-dir", dirfd >= 0, "errno %d (%s)", errno, strerror(errno));
+    if (dirfd >= 0) {
+        CHECK_ERRNO("pread-dir", (int)pread(dirfd, buf, 1, 0), EISDIR);
+        close(dirfd);
+    }
+    CHECK_ERRNO("pread-stdin", (int)pread(0, buf, 1, 0), ESPIPE);
+    CHECK_ERRNO("seek-stdin", (int)lseek(0, 0, SEEK_CUR), ESPIPE);
+    __wasi_filesize_t tell_pos;
+    CHECK("tell-stdout", __wasi_fd_tell(1, &tell_pos) == __WASI_ERRNO_SPIPE, "not ESPIPE");
+    CHECK_ERRNO("seek-negative", (int)lseek(fd, -1, SEEK_SET), EINVAL);
+    CHECK_ERRNO("seek-before-start", (int)lseek(fd, -100, SEEK_END), EINVAL);
+    CHECK("seek-past-end", lseek(fd, 100, SEEK_END) == 113, "errno %d (%s)", errno, strerror(errno));
+    // Back to where renumber-moves expects
+    lseek(fd, 5, SEEK_SET);
+
+    int other = open("/work/sub/b.txt", O_RDONLY);
+    CHECK("renumber",
+        other >= 0 && __wasi_fd_renumber(fd, other) == __WASI_ERRNO_SUCCESS,
+        "open %d errno %d", other, errno);
+    n = read(other, buf, 7);
+    CHECK("renumber-moves", n == 7 && memcmp(buf, ", world", 7) == 0, "read %zd", n);
+    CHECK_ERRNO("renumber-closes", close(fd), EBADF);
+    CHECK("renumber-badf", __wasi_fd_renumber(fd, other) == __WASI_ERRNO_BADF, "renumbered a closed fd");
+    // The first preopen
+    CHECK("renumber-preopen", __wasi_fd_renumber(other, 3) == __WASI_ERRNO_NOTSUP, "renumbered over a preopen");
+    close(other);
+}
+
+// Calls that would change anything fail with an errno, rather than killing the plugin
+static void check_read_only(void) {
+    CHECK_ERRNO("mkdir", mkdir("/work/newdir", 0755), EACCES);
+    CHECK_ERRNO("rmdir", rmdir("/work/sub/deeper"), EACCES);
+    CHECK_ERRNO("unlink", unlink("/work/a.txt"), EACCES);
+    CHECK_ERRNO("rename", rename("/work/a.txt", "/work/b.txt"), EACCES);
+    CHECK_ERRNO("symlink", symlink("a.txt", "/work/link"), EACCES);
+    CHECK_ERRNO("link", link("/work/a.txt", "/work/link"), EACCES);
+
+    int fd = open("/work/a.txt", O_RDONLY);
+    if (fd < 0) {
+        CHECK("open", 0, "errno %d (%s)", errno, strerror(errno));
+        return;
+    }
+    CHECK("fsync", fsync(fd) == 0, "errno %d (%s)", errno, strerror(errno));
+    CHECK("fadvise", posix_fadvise(fd, 0, 0, POSIX_FADV_SEQUENTIAL) == 0, "failed");
+    CHECK_ERRNO("ftruncate", ftruncate(fd, 0), EBADF);
+    CHECK_ERRNO("pwrite", (int)pwrite(fd, "x", 1, 0), EBADF);
+    CHECK_ERRNO("fsync-badf", fsync(1000), EBADF);
+    close(fd);
+}
+
+// /tmp can be written to, up to a quota
+static void check_tmp(void) {
+    char buf[64];
+    int fd = open("/tmp/x.txt", O_WRONLY | O_CREAT | O_EXCL, 0644);
+    CHECK("tmp-create", fd >= 0, "errno %d (%s)", errno, strerror(errno));
+    if (fd < 0) {
+        return;
+    }
+    CHECK("tmp-write", write(fd, "hello", 5) == 5, "errno %d (%s)", errno, strerror(errno));
+    CHECK("tmp-pwrite", pwrite(fd, "J", 1, 0) == 1, "errno %d (%s)", errno, strerror(errno));
+    close(fd);
+    fd = open("/tmp/x.txt", O_WRONLY | O_APPEND);
+    CHECK("tmp-append", fd >= 0 && write(fd, "!", 1) == 1, "errno %d (%s)", errno, strerror(errno));
+    close(fd);
+    int n = read_all("/tmp/x.txt", buf, sizeof(buf));
+    CHECK("tmp-read", n == 6 && strcmp(buf, "Jello!") == 0, "read %d", n);
+
+    CHECK("tmp-mkdir", mkdir("/tmp/d", 0755) == 0, "errno %d (%s)", errno, strerror(errno));
+    CHECK_ERRNO("tmp-mkdir-exists", mkdir("/tmp/d", 0755), EEXIST);
+    CHECK("tmp-rename", rename("/tmp/x.txt", "/tmp/d/y.txt") == 0, "errno %d (%s)", errno, strerror(errno));
+    n = read_all("/tmp/d/y.txt", buf, sizeof(buf));
+    CHECK("tmp-renamed", n == 6 && access("/tmp/x.txt", F_OK) == -1, "read %d", n);
+    CHECK_ERRNO("tmp-rename-into-self", rename("/tmp/d", "/tmp/d/e"), EINVAL);
+    CHECK_ERRNO("tmp-rename-out", rename("/tmp/d/y.txt", "/work/y.txt"), EACCES);
+    CHECK_ERRNO("tmp-rmdir-notempty", rmdir("/tmp/d"), ENOTEMPTY);
+    CHECK("tmp-unlink", unlink("/tmp/d/y.txt") == 0, "errno %d (%s)", errno, strerror(errno));
+    CHECK("tmp-rmdir", rmdir("/tmp/d") == 0, "errno %d (%s)", errno, strerror(errno));
+
+    fd = open("/tmp/big", O_RDWR | O_CREAT | O_TRUNC, 0644);
+    CHECK("tmp-truncate", fd >= 0 && ftruncate(fd, 1 << 20) == 0, "errno %d (%s)", errno, strerror(errno));
+    CHECK_ERRNO("tmp-quota", ftruncate(fd, 65 << 20), ENOSPC);
+    // Sizes from the top of the range, with other files using some of the quota, mustn't overflow
+    // the accounting
+    int other = open("/tmp/other", O_WRONLY | O_CREAT | O_TRUNC, 0644);
+    CHECK("tmp-other", other >= 0 && write(other, "x", 1) == 1, "errno %d (%s)", errno, strerror(errno));
+    CHECK("tmp-truncate-max", __wasi_fd_filestat_set_size(fd, UINT64_MAX) == __WASI_ERRNO_FBIG, "not EFBIG");
+    CHECK("tmp-allocate-max", __wasi_fd_allocate(fd, 0, UINT64_MAX) == __WASI_ERRNO_FBIG, "not EFBIG");
+    close(other);
+    unlink("/tmp/other");
+    close(fd);
+    // Unlinking frees the space, so the quota can be used again
+    CHECK("tmp-unlink-big", unlink("/tmp/big") == 0, "errno %d (%s)", errno, strerror(errno));
+    fd = open("/tmp/big", O_RDWR | O_CREAT, 0644);
+    CHECK("tmp-quota-freed", fd >= 0 && ftruncate(fd, 63 << 20) == 0, "errno %d (%s)", errno, strerror(errno));
+    close(fd);
+    unlink("/tmp/big");
+}
+
+static int before(struct timespec a, struct timespec b) {
+    return a.tv_sec < b.tv_sec || (a.tv_sec == b.tv_sec && a.tv_nsec < b.tv_nsec);
+}
+
+// Time and randomness work, but are the same every run
+static void check_determinism(void) {
+    struct timespec a, b;
+    clock_gettime(CLOCK_REALTIME, &a);
+    clock_gettime(CLOCK_REALTIME, &b);
+    // There's no upload time for the test song, so the clock starts at the default epoch
+    CHECK("clock-realtime", a.tv_sec >= 1525132800 && a.tv_sec < 1525132800 + 60, "realtime %lld", (long long)a.tv_sec);
+    CHECK("clock-realtime-advances", before(a, b), "realtime didn't advance");
+    clock_gettime(CLOCK_MONOTONIC, &a);
+    clock_gettime(CLOCK_MONOTONIC, &b);
+    CHECK("clock-monotonic-advances", before(a, b), "monotonic didn't advance");
+
+    uint8_t r1[20], r2[20];
+    int ok = __wasi_random_get(r1, sizeof(r1)) == __WASI_ERRNO_SUCCESS &&
+        __wasi_random_get(r2, sizeof(r2)) == __WASI_ERRNO_SUCCESS;
+    CHECK("random", ok && memcmp(r1, r2, sizeof(r1)) != 0, "random_get failed or repeated itself");
+}
+
+int main(void) {
+    check_readdir();
+    check_paths();
+    check_fds();
+    check_read_only();
+    check_tmp();
+    check_determinism();
+
+    FILE *out = fopen("/out/result.json", "w");
+    CHECK("result", out != NULL && fputs("{}", out) >= 0 && fclose(out) == 0,
+        "errno %d (%s)", errno, strerror(errno));
+    printf("done\n");
+    return 0;
+}
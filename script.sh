@@ -40,7 +40,31 @@ elif [ "$1" = mkdb ]; then
     . .env
     db="$(echo "$DATABASE_URL" | sed 's/^sqlite://g')"
     rm -f "$db"
-    sqlite3 "$db" <schema.sql
+    # Mirrors `bsmeta migrate`, but without needing the binary - which can't be built until the
+    # database exists, because of sqlx's compile-time query checking
+    sqlite3 "$db" <migrations/schema_version.sql
+    for migration in migrations/[0-9]*.sql; do
+        name="$(basename "$migration" .sql)"
+        version="$(echo "$name" | sed 's/_.*//' | sed 's/^0*//')"
+        (
+            echo "BEGIN;"
+            cat "$migration"
+            echo "INSERT INTO schema_version (version, name, applied_at) VALUES ($version, '${name#*_}', $(date +%s000));"
+            echo "COMMIT;"
+        ) | sqlite3 "$db"
+    done
+
+elif [ "$1" = migrate ]; then
+    shift
+    RUST_BACKTRACE=1 WASI_ROOT=$(pwd)/wasmtime/crates/wasi-common/WASI cargo run $OPT -- migrate "$@"
+
+elif [ "$1" = export ]; then
+    shift
+    RUST_BACKTRACE=1 WASI_ROOT=$(pwd)/wasmtime/crates/wasi-common/WASI cargo run $OPT -- export "$@"
+
+elif [ "$1" = aggregate ]; then
+    shift
+    RUST_BACKTRACE=1 WASI_ROOT=$(pwd)/wasmtime/crates/wasi-common/WASI cargo run $OPT -- aggregate "$@"
 
 elif [ "$1" = plugins ]; then
     shift
@@ -71,6 +95,9 @@ elif [ "$1" = plugins ]; then
         cd ..
         wasicc -Wl,--allow-undefined -Wall -O2 -o dist/js.wasm interp-js.c quickjs/libquickjs.a
 
+        # WASI conformance checks for the plugin runtime, run by `bsmeta test-wasi`
+        wasicc -Wall -O2 -o dist/wasi-test.wasm wasi-test.c
+
         # Python - with help from https://github.com/aidanhs/empython
         cd cpython
         git reset --hard
@@ -123,11 +150,10 @@ elif [ "$1" = plugins ]; then
         cd ..
 
     elif [ "$arg" = build-plugins ]; then
-        cd plugins
-        mkdir -p dist
-        rm -f dist/*.tar
+        mkdir -p plugins/dist
+        rm -f plugins/dist/*.tar
 
-        ./genplugins.py
+        ./script.sh run build-plugins
 
     elif [ "$arg" = rebuild ]; then
         rm -rf plugins/dist
@@ -0,0 +1,195 @@
+//! Running aggregate analyses, which look across many songs at once - like percentile ranks of
+//! difficulty metrics, or trends in an uploader's maps
+use anyhow::{Context, Result, anyhow, bail};
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+
+use super::db::{self, SqliteConnection, retry_busy};
+use super::models::BeatSaverMap;
+use super::plugins::{self, PluginEntry};
+use super::wasm::{self, AggregateGroup, PluginInfo, PluginLevel};
+use super::{num_to_key, parse_key};
+
+/// What aggregate plugins see of each song, as a line of `/data/songs.jsonl`
+#[derive(Serialize)]
+struct AggregateSong {
+    key: String,
+    hash: String,
+    name: String,
+    sub_name: String,
+    uploader: String,
+    uploaded_at_tstamp: i64,
+    upvotes: u32,
+    downvotes: u32,
+    extra: Option<Value>,
+    /// Map analysis results, by plugin name
+    analyses: BTreeMap<String, Value>,
+}
+
+// A song ready to be handed to plugins
+struct LoadedSong {
+    key: i64,
+    uploader: String,
+    song: AggregateSong,
+}
+
+/// Run every aggregate plugin (or just those named in `only`) over the songs that haven't been
+/// deleted, replacing their previous results. A plugin failing doesn't stop the others, but does
+/// leave its previous results in place - as does failing on just some groups of songs, for the
+/// songs in those groups.
+pub fn run_aggregates(conn: &SqliteConnection, only: Option<&[String]>) -> Result<()> {
+    let plugins: Vec<(String, PluginEntry)> = plugins::plugin_list()?
+        .into_iter()
+        .filter(|(_, entry)| entry.info.level == PluginLevel::Aggregate)
+        .filter(|(name, _)| only.map_or(true, |only| only.contains(name)))
+        .collect();
+    if let Some(only) = only {
+        for name in only {
+            if !plugins.iter().any(|(n, _)| n == name) {
+                bail!("no aggregate plugin called {}", name)
+            }
+        }
+    }
+    if plugins.is_empty() {
+        info!("No aggregate plugins to run");
+        return Ok(())
+    }
+
+    let songs = load_songs(conn)?;
+    info!("Loaded {} songs for aggregate analyses", songs.len());
+
+    let mut failed = vec![];
+    for (name, entry) in plugins {
+        info!("Running aggregate plugin {}", name);
+        match run_aggregate(&name, &entry, &songs) {
+            Ok(AggregateRun { results, failed_groups, unchanged }) => {
+                info!("Aggregate plugin {} produced results for {} songs", name, results.len());
+                retry_busy(|| db::replace_song_aggregates(conn, name.clone(), results.clone(), unchanged.clone()))
+                    .with_context(|| format!("failed to save results of {}", name))?;
+                if !failed_groups.is_empty() {
+                    failed.push(format!("{} (on {} groups)", name, failed_groups.len()))
+                }
+            },
+            Err(e) => {
+                warn!("Aggregate plugin {} failed: {:#}", name, e);
+                failed.push(name)
+            },
+        }
+    }
+    if !failed.is_empty() {
+        bail!("aggregate plugins failed: {}", failed.join(", "))
+    }
+    Ok(())
+}
+
+fn load_songs(conn: &SqliteConnection) -> Result<Vec<LoadedSong>> {
+    let songs = retry_busy(|| db::export_songs(conn, 0, i64::MAX, Some(false))).context("failed to load songs")?;
+    let mut loaded = Vec::with_capacity(songs.len());
+    for song in songs {
+        let (hash, bsmeta) = match (song.hash, song.bsmeta) {
+            (Some(hash), Some(bsmeta)) => (hash, bsmeta),
+            _ => continue,
+        };
+        let key_str = num_to_key(song.key);
+        let bsmeta: BeatSaverMap = serde_json::from_slice(&bsmeta)
+            .with_context(|| format!("failed to deserialize bsmeta for {}", key_str))?;
+        let extra = song.extra_meta
+            .map(|extra_meta| serde_json::from_slice(&extra_meta))
+            .transpose()
+            .with_context(|| format!("failed to deserialize extra meta for {}", key_str))?;
+        let mut analyses = BTreeMap::new();
+        for (analysis_name, result) in retry_busy(|| db::get_song_analyses(conn, &hash)).context("failed to load analyses")? {
+            let result: Value = serde_json::from_slice(&result)
+                .with_context(|| format!("failed to deserialize analysis {} for {}", analysis_name, key_str))?;
+            analyses.insert(analysis_name, result);
+        }
+        loaded.push(LoadedSong {
+            key: song.key,
+            uploader: bsmeta.uploader.name.clone(),
+            song: AggregateSong {
+                key: key_str,
+                hash,
+                name: bsmeta.metadata.song_name,
+                sub_name: bsmeta.metadata.song_sub_name,
+                uploader: bsmeta.uploader.name,
+                uploaded_at_tstamp: bsmeta.uploaded.timestamp(),
+                upvotes: bsmeta.stats.upvotes,
+                downvotes: bsmeta.stats.downvotes,
+                extra,
+                analyses,
+            },
+        })
+    }
+    Ok(loaded)
+}
+
+// What a plugin output over all the groups it ran on
+struct AggregateRun {
+    /// The (key, result JSON) of every song it output results for
+    results: Vec<(i64, Vec<u8>)>,
+    /// Groups it failed on, which don't stop it running on the rest
+    failed_groups: Vec<String>,
+    /// The songs in failed groups, whose previous results should be kept
+    unchanged: Vec<i64>,
+}
+
+// Run a plugin over each group of songs
+fn run_aggregate(name: &str, entry: &PluginEntry, songs: &[LoadedSong]) -> Result<AggregateRun> {
+    let info = &entry.info;
+    let plugin = entry.load(name)?;
+
+    let mut groups: BTreeMap<&str, Vec<&LoadedSong>> = BTreeMap::new();
+    for song in songs {
+        let group = match info.group.unwrap_or_default() {
+            AggregateGroup::All => "",
+            AggregateGroup::Uploader => song.uploader.as_str(),
+        };
+        groups.entry(group).or_default().push(song)
+    }
+
+    let mut run = AggregateRun { results: vec![], failed_groups: vec![], unchanged: vec![] };
+    for (group, group_songs) in groups {
+        match run_group(&plugin, info, &group_songs) {
+            Ok(results) => run.results.extend(results),
+            Err(e) => {
+                warn!("Aggregate plugin {} failed on group {:?}: {:#}", name, group, e);
+                run.failed_groups.push(group.to_owned());
+                run.unchanged.extend(group_songs.iter().map(|song| song.key))
+            },
+        }
+    }
+    Ok(run)
+}
+
+// Run a plugin over one group of songs, returning the (key, result JSON) of every song it output
+// results for
+fn run_group(plugin: &wasm::AnalysisPlugin, info: &PluginInfo, group_songs: &[&LoadedSong]) -> Result<Vec<(i64, Vec<u8>)>> {
+    let mut jsonl = vec![];
+    for song in group_songs.iter() {
+        let mut song_json = serde_json::to_value(&song.song).expect("failed to serialize song");
+        // Only pass on the analyses the plugin asked for
+        if let Some(inputs) = &info.inputs {
+            let analyses: BTreeMap<&String, &Value> = song.song.analyses.iter()
+                .filter(|(analysis_name, _)| inputs.contains(analysis_name))
+                .collect();
+            song_json["analyses"] = serde_json::to_value(analyses).expect("failed to serialize analyses");
+        }
+        serde_json::to_writer(&mut jsonl, &song_json).expect("failed to serialize song");
+        jsonl.push(b'\n');
+    }
+
+    let (log, res) = plugin.run_aggregate(jsonl)?;
+    let output = res.with_context(|| format!("failed to run, log:{{#\n{}\n#}}", log))?;
+    let group_keys: HashSet<i64> = group_songs.iter().map(|song| song.key).collect();
+    let mut results = vec![];
+    for (key_str, values) in output {
+        let key = parse_key(&key_str).ok_or_else(|| anyhow!("output for invalid song key {:?}", key_str))?;
+        if !group_keys.contains(&key) {
+            bail!("output for song {}, which it wasn't given", key_str)
+        }
+        results.push((key, serde_json::to_vec(&values).expect("failed to serialize results")))
+    }
+    Ok(results)
+}
@@ -0,0 +1,306 @@
+use anyhow::{Context, Result, anyhow, bail};
+use decorum::R32;
+use log::debug;
+use serde::{Serialize, Deserialize};
+use std::convert::{TryFrom, TryInto};
+use std::io;
+
+/// The codec of a song file, as detected from its content (the extension is meaningless - .egg
+/// files are just renamed ogg files, and some maps have ogg-named files in other formats)
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    Vorbis,
+    Opus,
+    Wav,
+    Unknown,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct AudioInfo {
+    pub codec: AudioCodec,
+    pub duration: R32,
+    pub sample_rate: u32,
+    pub channels: u8,
+}
+
+/// Identify the codec of some audio data, without trying to parse it
+pub fn detect_codec(data: &[u8]) -> AudioCodec {
+    if data.starts_with(b"OggS") {
+        match first_ogg_packet(data) {
+            Ok(pck) if pck.starts_with(b"\x01vorbis") => AudioCodec::Vorbis,
+            Ok(pck) if pck.starts_with(b"OpusHead") => AudioCodec::Opus,
+            _ => AudioCodec::Unknown,
+        }
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WAVE" {
+        AudioCodec::Wav
+    } else {
+        AudioCodec::Unknown
+    }
+}
+
+/// Work out the codec, duration, sample rate and channel count of some audio data
+pub fn probe(data: &[u8]) -> Result<AudioInfo> {
+    let codec = detect_codec(data);
+    debug!("Detected audio codec {:?}", codec);
+    match codec {
+        AudioCodec::Vorbis => probe_vorbis(data),
+        AudioCodec::Opus => probe_opus(data),
+        AudioCodec::Wav => probe_wav(data),
+        AudioCodec::Unknown => bail!("unrecognised audio format"),
+    }
+}
+
+fn probe_vorbis(ogg: &[u8]) -> Result<AudioInfo> {
+    let ogg = io::Cursor::new(ogg);
+    let mut srr = lewton::inside_ogg::OggStreamReader::new(ogg).context("failed to create ogg stream reader")?;
+
+    let sample_rate = srr.ident_hdr.audio_sample_rate;
+    let channels = srr.ident_hdr.audio_channels;
+    if sample_rate == 0 {
+        bail!("vorbis stream has a zero sample rate")
+    }
+
+    let mut n = 0;
+    let mut len_play = R32::from_inner(0.0);
+    while let Some(pck) = srr.read_dec_packet().context("failed to read packet")? {
+        n += 1;
+        // This is guaranteed by the docs
+        assert_eq!(pck.len(), channels as usize);
+        len_play += pck[0].len() as f32 / sample_rate as f32;
+    }
+    debug!("The vorbis stream is {} s long ({} packets)", len_play, n);
+    Ok(AudioInfo { codec: AudioCodec::Vorbis, duration: len_play, sample_rate, channels })
+}
+
+// Opus always decodes at 48kHz, granule positions are in units of 48kHz samples regardless of the
+// 'input sample rate' in the header
+// https://datatracker.ietf.org/doc/html/rfc7845#section-4
+const OPUS_GRANULE_RATE: u32 = 48000;
+
+fn probe_opus(ogg: &[u8]) -> Result<AudioInfo> {
+    let head = first_ogg_packet(ogg)?;
+    // magic (8), version (1), channel count (1), pre-skip (2), input sample rate (4)
+    if head.len() < 16 {
+        bail!("opus header too short")
+    }
+    let channels = head[9];
+    let pre_skip = u16::from_le_bytes([head[10], head[11]]);
+    let input_sample_rate = u32::from_le_bytes([head[12], head[13], head[14], head[15]]);
+    // An input sample rate of zero means unspecified
+    let sample_rate = if input_sample_rate == 0 { OPUS_GRANULE_RATE } else { input_sample_rate };
+
+    let last_granule = ogg_pages(ogg)
+        .map(|page| page.map(|p| p.granule))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        // -1 indicates no packet finishes on the page
+        .filter(|&g| g != -1)
+        .last()
+        .ok_or_else(|| anyhow!("no opus audio pages"))?;
+    let samples = last_granule.checked_sub(pre_skip.into()).filter(|&s| s >= 0)
+        .ok_or_else(|| anyhow!("opus granule position {} is before pre-skip {}", last_granule, pre_skip))?;
+    let duration = R32::from_inner(samples as f32 / OPUS_GRANULE_RATE as f32);
+    debug!("The opus stream is {} s long", duration);
+    Ok(AudioInfo { codec: AudioCodec::Opus, duration, sample_rate, channels })
+}
+
+fn probe_wav(wav: &[u8]) -> Result<AudioInfo> {
+    let fmt = wav_fmt(wav)?;
+    let data_len = wav_chunk(wav, b"data")?.map(|body| body.len())
+        .ok_or_else(|| anyhow!("no data chunk in wav"))?;
+    if fmt.byte_rate == 0 {
+        bail!("wav has a zero byte rate")
+    }
+    let channels = fmt.channels.try_into().context("too many wav channels")?;
+    let duration = R32::from_inner(data_len as f32 / fmt.byte_rate as f32);
+    debug!("The wav is {} s long", duration);
+    Ok(AudioInfo { codec: AudioCodec::Wav, duration, sample_rate: fmt.sample_rate, channels })
+}
+
+struct WavFmt {
+    format_tag: u16,
+    channels: u16,
+    sample_rate: u32,
+    byte_rate: u32,
+    bits_per_sample: u16,
+}
+
+fn wav_fmt(wav: &[u8]) -> Result<WavFmt> {
+    let body = wav_chunk(wav, b"fmt ")?
+        .ok_or_else(|| anyhow!("no fmt chunk in wav"))?;
+    if body.len() < 16 {
+        bail!("wav fmt chunk too short")
+    }
+    Ok(WavFmt {
+        format_tag: u16::from_le_bytes([body[0], body[1]]),
+        channels: u16::from_le_bytes([body[2], body[3]]),
+        sample_rate: u32::from_le_bytes(body[4..8].try_into().expect("slice is 4 long")),
+        byte_rate: u32::from_le_bytes(body[8..12].try_into().expect("slice is 4 long")),
+        bits_per_sample: u16::from_le_bytes([body[14], body[15]]),
+    })
+}
+
+// The (id, body) of each chunk in a wav. The last chunk may be truncated (or have a bogus length
+// if written by a streaming encoder), so its body is whatever is actually present.
+fn wav_chunks(wav: &[u8]) -> impl Iterator<Item=Result<(&[u8], &[u8])>> + '_ {
+    // Skip the RIFF header
+    let mut rest = wav.get(12..).unwrap_or(&[]);
+    std::iter::from_fn(move || {
+        if rest.len() < 8 {
+            return None
+        }
+        let id = &rest[..4];
+        let len = u32::from_le_bytes(rest[4..8].try_into().expect("slice is 4 long"));
+        // Chunks are padded to an even length
+        let sizes = usize::try_from(len).ok()
+            .and_then(|len| Some((len, len.checked_add(len % 2)?.checked_add(8)?)));
+        let (len, skip) = match sizes {
+            Some(sizes) => sizes,
+            None => {
+                rest = &[];
+                return Some(Err(anyhow!("wav chunk length {} is too big", len)))
+            },
+        };
+        let body = &rest[8..];
+        let body = &body[..len.min(body.len())];
+        rest = if skip > rest.len() { &[] } else { &rest[skip..] };
+        Some(Ok((id, body)))
+    })
+}
+
+// The body of the first chunk in a wav with an id
+fn wav_chunk<'a>(wav: &'a [u8], want: &[u8]) -> Result<Option<&'a [u8]>> {
+    for chunk in wav_chunks(wav) {
+        let (id, body) = chunk?;
+        if id == want {
+            return Ok(Some(body))
+        }
+    }
+    Ok(None)
+}
+
+// The format tag of integer PCM in a wav fmt chunk
+const WAV_FORMAT_PCM: u16 = 1;
+
+/// Decode audio data to a single channel (averaging any others), returning the samples in the
+/// range -1 to 1 along with the sample rate. Only vorbis and 16 bit PCM wav can be decoded.
+pub fn decode_mono(data: &[u8]) -> Result<(u32, Vec<f32>)> {
+    match detect_codec(data) {
+        AudioCodec::Vorbis => decode_vorbis_mono(data),
+        AudioCodec::Wav => decode_wav_mono(data),
+        codec => bail!("can't decode {:?} audio", codec),
+    }
+}
+
+fn decode_vorbis_mono(ogg: &[u8]) -> Result<(u32, Vec<f32>)> {
+    let ogg = io::Cursor::new(ogg);
+    let mut srr = lewton::inside_ogg::OggStreamReader::new(ogg).context("failed to create ogg stream reader")?;
+    let sample_rate = srr.ident_hdr.audio_sample_rate;
+    if sample_rate == 0 {
+        bail!("vorbis stream has a zero sample rate")
+    }
+    let mut samples = vec![];
+    while let Some(pck) = srr.read_dec_packet().context("failed to read packet")? {
+        samples.extend(mix_to_mono(&pck, |s: &i16| *s as f32 / 32768.0));
+    }
+    Ok((sample_rate, samples))
+}
+
+fn decode_wav_mono(wav: &[u8]) -> Result<(u32, Vec<f32>)> {
+    let fmt = wav_fmt(wav)?;
+    if fmt.format_tag != WAV_FORMAT_PCM || fmt.bits_per_sample != 16 {
+        bail!("can't decode wav with format {} and {} bits per sample", fmt.format_tag, fmt.bits_per_sample)
+    }
+    if fmt.channels == 0 || fmt.sample_rate == 0 {
+        bail!("wav has no channels or a zero sample rate")
+    }
+    let data = wav_chunk(wav, b"data")?
+        .ok_or_else(|| anyhow!("no data chunk in wav"))?;
+    let channels = fmt.channels as usize;
+    let samples = data.chunks_exact(2 * channels)
+        .map(|frame| {
+            let sum: f32 = frame.chunks_exact(2).map(|s| i16::from_le_bytes([s[0], s[1]]) as f32 / 32768.0).sum();
+            sum / channels as f32
+        })
+        .collect();
+    Ok((fmt.sample_rate, samples))
+}
+
+fn mix_to_mono<'a, T>(channels: &'a [Vec<T>], to_f32: impl Fn(&T) -> f32 + 'a) -> impl Iterator<Item=f32> + 'a {
+    let len = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+    (0..len).map(move |i| channels.iter().map(|c| to_f32(&c[i])).sum::<f32>() / channels.len() as f32)
+}
+
+struct OggPage<'a> {
+    granule: i64,
+    data: &'a [u8],
+}
+
+// https://datatracker.ietf.org/doc/html/rfc3533#section-6
+fn ogg_pages(ogg: &[u8]) -> impl Iterator<Item=Result<OggPage<'_>>> + '_ {
+    let mut rest = ogg;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None
+        }
+        const HEADER_LEN: usize = 27;
+        if rest.len() < HEADER_LEN || &rest[..4] != b"OggS" {
+            rest = &[];
+            return Some(Err(anyhow!("invalid ogg page header")))
+        }
+        let granule = i64::from_le_bytes(rest[6..14].try_into().expect("slice is 8 long"));
+        let num_segments = rest[26] as usize;
+        let segments_end = HEADER_LEN + num_segments;
+        if rest.len() < segments_end {
+            rest = &[];
+            return Some(Err(anyhow!("truncated ogg segment table")))
+        }
+        let data_len: usize = rest[HEADER_LEN..segments_end].iter().map(|&s| s as usize).sum();
+        if rest.len() < segments_end + data_len {
+            rest = &[];
+            return Some(Err(anyhow!("truncated ogg page")))
+        }
+        let data = &rest[segments_end..segments_end + data_len];
+        rest = &rest[segments_end + data_len..];
+        Some(Ok(OggPage { granule, data }))
+    })
+}
+
+// The identification header of both vorbis and opus must be alone on the first page
+fn first_ogg_packet(ogg: &[u8]) -> Result<&[u8]> {
+    let page = ogg_pages(ogg).next().ok_or_else(|| anyhow!("empty ogg stream"))??;
+    Ok(page.data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav(chunks: &[u8]) -> Vec<u8> {
+        let mut wav = b"RIFF\0\0\0\0WAVE".to_vec();
+        wav.extend_from_slice(b"fmt \x10\0\0\0");
+        // PCM, 1 channel, 8000 Hz, 16000 bytes/s, 2 byte frames, 16 bits
+        wav.extend_from_slice(&[1, 0, 1, 0, 0x40, 0x1f, 0, 0, 0x80, 0x3e, 0, 0, 2, 0, 16, 0]);
+        wav.extend_from_slice(chunks);
+        wav
+    }
+
+    #[test]
+    fn wav_oversized_chunk() {
+        // Like a streaming encoder that never went back to fill in the length
+        let mut data = b"data\xff\xff\xff\xff".to_vec();
+        data.extend_from_slice(&[0; 1600]);
+        let info = probe(&wav(&data)).unwrap();
+        assert_eq!(info.duration, R32::from_inner(0.1));
+    }
+
+    #[test]
+    fn wav_truncated_chunk_header() {
+        let err = probe(&wav(b"data\x10\0")).unwrap_err();
+        assert_eq!(err.to_string(), "no data chunk in wav");
+        let err = probe(b"RIFF\0\0\0\0WAVEfmt ").unwrap_err();
+        assert_eq!(err.to_string(), "no fmt chunk in wav");
+    }
+}
@@ -0,0 +1,210 @@
+//! Retrieving maps and their metadata from BeatSaver (and the bsaber.org cache of it)
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::Deserialize;
+use std::thread;
+use std::time;
+
+use super::models::BeatSaverMap;
+use super::num_to_key;
+
+pub const INFO_PAUSE: time::Duration = time::Duration::from_secs(3);
+const BSABER_DL_PAUSE: time::Duration = time::Duration::from_secs(10);
+const BEATSAVER_DL_PAUSE: time::Duration = time::Duration::from_secs(120);
+
+// Additional padding to apply when ratelimited, to prove we're being a good citizen
+const RATELIMIT_PADDING: time::Duration = time::Duration::from_secs(60);
+
+pub fn make_client() -> reqwest::blocking::Client {
+    reqwest::blocking::Client::builder()
+        .user_agent("aidanhsmetaclient/0.1 (@aidanhs#1789 on discord, aidanhs@cantab.net, https://github.com/aidanhs/bsmeta)")
+        .build().expect("failed to create reqwest client")
+}
+
+// TODO: do this by implementing a Deserializer that creates a visitor that dispatches to two sub
+// visitors
+fn splitde<'de, D>(de: D) -> Result<Vec<(BeatSaverMap, Box<serde_json::value::RawValue>)>, D::Error> where D: serde::Deserializer<'de> {
+    use serde::de::Error;
+    let raws = Vec::<Box<serde_json::value::RawValue>>::deserialize(de)?;
+    let all: Vec<_> = raws.into_iter()
+        .map(|rv| {
+            serde_json::from_str(rv.get())
+                .map(|m| (m, rv))
+                .map_err(|e| D::Error::custom(e))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(all)
+}
+
+#[derive(Deserialize)]
+pub struct BeatSaverLatestResponse {
+    #[serde(deserialize_with = "splitde")]
+    pub docs: Vec<(BeatSaverMap, Box<serde_json::value::RawValue>)>,
+}
+
+macro_rules! retry {
+    ($t:expr, $e:expr) => {{
+        println!("request failure: {}", $e);
+        thread::sleep($t);
+        continue
+    }};
+}
+
+const RATELIMIT_RESET_AFTER_HEADER: &str = "x-ratelimit-reset-after";
+
+pub fn get_song_zip(client: &reqwest::blocking::Client, hash: &str, last_bsaber_dl: &mut time::Instant, last_beatsaver_dl: &mut time::Instant) -> Result<Vec<u8>> {
+    //let url = format!("https://cdn.beatsaver.com/{}.zip", hash);
+    //println!("Retrieving {} from url {}", hash, url);
+    //let mut res = client.get(&url).send().expect("failed to send request");
+    //println!("Got response {}", res.status());
+    //if res.status() == reqwest::StatusCode::UNAUTHORIZED {
+    //    let url = format!("https://bsaber.org/files/cache/zip/{}.zip", hash);
+    //    println!("Retrying with url {}", url);
+    //    res = client.get(&url).send().expect("failed to send request");
+    //    println!("Got response {}", res.status());
+    //}
+
+    let mut attempts = 2;
+    loop {
+        if attempts == 0 {
+            break
+        }
+        attempts -= 1;
+
+        let pause_remaining = BSABER_DL_PAUSE.saturating_sub(last_bsaber_dl.elapsed());
+        if !pause_remaining.is_zero() {
+            thread::sleep(pause_remaining)
+        }
+        *last_bsaber_dl = time::Instant::now();
+        println!("Retrieving {} from bsaber.org", hash);
+        let (res, headers) = match do_req(client, &format!("https://bsaber.org/files/cache/zip/{}.zip", hash)) {
+            Ok(r) => r,
+            Err(e) => retry!(BSABER_DL_PAUSE, format!("failed to send request: {}", e)),
+        };
+        println!("Got response {}", res.status());
+
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            break
+        }
+        if !res.status().is_success() {
+            retry!(BSABER_DL_PAUSE, format!("non-success response: {:?} {:?}", headers, res.bytes()))
+        }
+        let bytes = match res.bytes() {
+            Ok(bs) => bs,
+            Err(e) => retry!(BSABER_DL_PAUSE, format!("failed to get bytes: {}, response headers: {:?}", e, headers)),
+        };
+
+        return Ok(bytes.as_ref().to_owned())
+    }
+
+    // TODO: ratelimit pause
+    println!("Falling back to beatsaver.com for song");
+    let mut attempts = 2;
+    loop {
+        if attempts == 0 {
+            bail!("multiple attempts to retrieve failed, from both bsaber.org and beatsaver.com")
+        }
+        attempts -= 1;
+
+        let pause_remaining = BEATSAVER_DL_PAUSE.saturating_sub(last_beatsaver_dl.elapsed());
+        if !pause_remaining.is_zero() {
+            thread::sleep(pause_remaining)
+        }
+        *last_beatsaver_dl = time::Instant::now();
+        println!("Retrieving {} from beatsaver.com", hash);
+        let (res, headers) = match do_req(client, &format!("https://cdn.beatsaver.com/{}.zip", hash)) {
+            Ok(r) => r,
+            Err(e) => retry!(BEATSAVER_DL_PAUSE, format!("failed to send request: {}", e)),
+        };
+        println!("Got response {}", res.status());
+
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            bail!("song not found on bsaber.org or beatsaver.com")
+        }
+        if !res.status().is_success() {
+            retry!(BEATSAVER_DL_PAUSE, format!("non-success response: {:?} {:?}", headers, res.bytes()))
+        }
+        let bytes = match res.bytes() {
+            Ok(bs) => bs,
+            Err(e) => retry!(BEATSAVER_DL_PAUSE, format!("failed to get bytes: {}, response headers: {:?}", e, headers)),
+        };
+
+        return Ok(bytes.as_ref().to_owned())
+    }
+}
+
+pub fn get_latest_maps(client: &reqwest::blocking::Client, before: DateTime<Utc>) -> Result<BeatSaverLatestResponse> {
+    println!("Getting maps before {}", before);
+    let before = before.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    let (res, headers) = do_req(client, &format!("https://api.beatsaver.com/maps/latest?automapper=true&sort=UPDATED&before={}", before))?;
+    println!("Got response {}", res.status());
+
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        bail!("latest maps page not found from beatsaver")
+    }
+    if !res.status().is_success() {
+        bail!("non-success response: {:?} {:?}", headers, res.bytes())
+    }
+    let bytes = match res.bytes() {
+        Ok(bs) => bs,
+        Err(e) => bail!("failed to get bytes: {}, response headers: {:?}", e, headers),
+    };
+
+    let bytes = bytes.as_ref();
+    let res = serde_json::from_slice(bytes)
+        .with_context(|| format!("failed to deserialize maps response: {:?}", String::from_utf8_lossy(bytes)))?;
+    Ok(res)
+}
+
+pub fn get_map_meta(client: &reqwest::blocking::Client, key: i64) -> Result<Option<(BeatSaverMap, Box<serde_json::value::RawValue>)>> {
+    let mut did_ratelimit = false;
+
+    loop {
+        let key_str = num_to_key(key);
+        println!("Getting map detail for {}", key_str);
+        let (res, headers) = do_req(client, &format!("https://api.beatsaver.com/maps/id/{}", key_str))?;
+        println!("Got response {}", res.status());
+
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None)
+        }
+        if res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS && !did_ratelimit {
+            did_ratelimit = true;
+            let pause = ratelimit_pause(&headers)?;
+            retry!(pause, format!("hit ratelimit, waiting {}s: {:?}", pause.as_secs(), headers))
+        }
+        if !res.status().is_success() {
+            bail!("non-success response: {:?} {:?}", headers, res.bytes())
+        }
+        let bytes = match res.bytes() {
+            Ok(bs) => bs,
+            Err(e) => bail!("failed to get bytes: {}, response headers: {:?}", e, headers),
+        };
+
+        let bytes = bytes.as_ref();
+        let raw_res: Box<serde_json::value::RawValue> = serde_json::from_slice(bytes)
+            .with_context(|| format!("failed to deserialize aw maps response: {:?}", String::from_utf8_lossy(bytes)))?;
+        let res = serde_json::from_str(raw_res.get())
+            .with_context(|| format!("failed to deserialize maps response: {:?}", String::from_utf8_lossy(bytes)))?;
+        return Ok(Some((res, raw_res)))
+    }
+}
+
+fn do_req(client: &reqwest::blocking::Client, url: &str) -> Result<(reqwest::blocking::Response, reqwest::header::HeaderMap)> {
+    debug!("request to url: {}", url);
+    client.get(url).send()
+        .map(|res| {
+            let headers = res.headers().to_owned();
+            (res, headers)
+        })
+        .context("failed to send request")
+}
+
+fn ratelimit_pause(headers: &reqwest::header::HeaderMap) -> Result<time::Duration> {
+    let r = headers.get(RATELIMIT_RESET_AFTER_HEADER)
+        .ok_or_else(|| anyhow!("no ratelimit header with too many requests response: {:?}", headers))?;
+    let r = r.to_str().with_context(|| format!("ratelimit header couldn't be interpreted as ascii: {:?}", headers))?;
+    let r: u64 = r.parse().with_context(|| format!("failed to parse ratelimit as i64: {:?}", headers))?;
+    Ok(2*time::Duration::from_millis(r) + RATELIMIT_PADDING) // pad for safety
+}
@@ -0,0 +1,485 @@
+//! Processing of map zips into the dat files that analyses need
+use anyhow::{Context, Result, anyhow, bail};
+use log::{debug, warn};
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::io::{self, Read};
+
+use super::audio;
+use super::models::ExtraMeta;
+
+#[derive(Deserialize)]
+pub struct InfoDat {
+    #[serde(rename = "_version", default)]
+    pub version: Option<String>,
+    #[serde(rename = "_songName", default)]
+    pub song_name: Option<String>,
+    #[serde(rename = "_songSubName", default)]
+    pub song_sub_name: Option<String>,
+    #[serde(rename = "_songAuthorName", default)]
+    pub song_author_name: Option<String>,
+    #[serde(rename = "_levelAuthorName", default)]
+    pub level_author_name: Option<String>,
+    #[serde(rename = "_beatsPerMinute", default)]
+    pub bpm: Option<f64>,
+    #[serde(rename = "_songFilename")]
+    pub song_filename: String,
+    #[serde(rename = "_coverImageFilename", default)]
+    pub cover_image_filename: Option<String>,
+    #[serde(rename = "_difficultyBeatmapSets")]
+    pub difficulty_beatmap_sets: Vec<DifficultySet>,
+}
+#[derive(Deserialize)]
+pub struct DifficultySet {
+    // Optional so that maps that leave these out can still be downloaded, even if they can't be
+    // analysed per beatmap
+    #[serde(rename = "_beatmapCharacteristicName")]
+    pub characteristic_name: Option<String>,
+    #[serde(rename = "_difficultyBeatmaps")]
+    pub difficulty_beatmaps: Vec<DifficultyBeatmap>,
+}
+#[derive(Deserialize)]
+pub struct DifficultyBeatmap {
+    #[serde(rename = "_difficulty")]
+    pub difficulty: Option<String>,
+    #[serde(rename = "_beatmapFilename")]
+    pub beatmap_filename: String,
+    #[serde(rename = "_noteJumpMovementSpeed", default)]
+    pub note_jump_speed: Option<f64>,
+    #[serde(rename = "_noteJumpStartBeatOffset", default)]
+    pub note_jump_start_beat_offset: Option<f64>,
+}
+
+/// The characteristics (sets of difficulties with different rules) that the game knows about
+pub const CHARACTERISTICS: &[&str] = &["Standard", "OneSaber", "NoArrows", "90Degree", "360Degree", "Lightshow", "Lawless"];
+/// The difficulties a characteristic can have, easiest first
+pub const DIFFICULTIES: &[&str] = &["Easy", "Normal", "Hard", "Expert", "ExpertPlus"];
+
+/// A map's info.dat, in the shape given to plugins
+#[derive(Debug, Clone, Serialize)]
+pub struct MapInfo {
+    pub version: Option<String>,
+    pub song_name: Option<String>,
+    pub song_sub_name: Option<String>,
+    pub song_author_name: Option<String>,
+    pub level_author_name: Option<String>,
+    pub bpm: Option<f64>,
+    pub beatmaps: Vec<BeatmapInfo>,
+}
+
+/// One difficulty of one characteristic in a map
+#[derive(Debug, Clone, Serialize)]
+pub struct BeatmapInfo {
+    pub characteristic: String,
+    pub difficulty: String,
+    pub filename: String,
+    pub note_jump_speed: Option<f64>,
+    pub note_jump_start_beat_offset: Option<f64>,
+}
+
+/// Parse an info.dat, failing if any beatmap can't be identified by its characteristic and
+/// difficulty
+pub fn parse_map_info(infodat: &[u8]) -> Result<MapInfo> {
+    let infodat: InfoDat = serde_json::from_slice(infodat).context("failed to parse info.dat")?;
+    let mut seen = HashSet::new();
+    let mut beatmaps = vec![];
+    for set in infodat.difficulty_beatmap_sets {
+        let characteristic = set.characteristic_name.ok_or_else(|| anyhow!("difficulty set with no characteristic"))?;
+        for beatmap in set.difficulty_beatmaps {
+            let difficulty = beatmap.difficulty.ok_or_else(|| anyhow!("{} beatmap with no difficulty", characteristic))?;
+            if !seen.insert((characteristic.clone(), difficulty.clone())) {
+                bail!("multiple {} {} beatmaps", characteristic, difficulty)
+            }
+            beatmaps.push(BeatmapInfo {
+                characteristic: characteristic.clone(),
+                difficulty,
+                filename: beatmap.beatmap_filename,
+                note_jump_speed: beatmap.note_jump_speed,
+                note_jump_start_beat_offset: beatmap.note_jump_start_beat_offset,
+            })
+        }
+    }
+    Ok(MapInfo {
+        version: infodat.version,
+        song_name: infodat.song_name,
+        song_sub_name: infodat.song_sub_name,
+        song_author_name: infodat.song_author_name,
+        level_author_name: infodat.level_author_name,
+        bpm: infodat.bpm,
+        beatmaps,
+    })
+}
+
+/// The content of a beatmap, the same whichever version of the format it was written in. Times
+/// are in beats, and colors are 0 for red (left) and 1 for blue (right).
+#[derive(Debug, Clone, Serialize)]
+pub struct Beatmap {
+    pub version: String,
+    pub notes: Vec<Note>,
+    pub bombs: Vec<Bomb>,
+    pub obstacles: Vec<Obstacle>,
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct Note {
+    pub time: f64,
+    pub line_index: i64,
+    pub line_layer: i64,
+    pub color: i64,
+    pub cut_direction: i64,
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct Bomb {
+    pub time: f64,
+    pub line_index: i64,
+    pub line_layer: i64,
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct Obstacle {
+    pub time: f64,
+    pub line_index: i64,
+    pub line_layer: i64,
+    pub duration: f64,
+    pub width: i64,
+    pub height: i64,
+}
+
+// Bombs are notes with their own type in v2
+const V2_BOMB_TYPE: i64 = 3;
+// v2 obstacles are either full height walls, or crouch walls over the top two layers
+const V2_CROUCH_OBSTACLE_TYPE: i64 = 1;
+const FULL_OBSTACLE_HEIGHT: i64 = 5;
+const CROUCH_OBSTACLE_LAYER: i64 = 2;
+const CROUCH_OBSTACLE_HEIGHT: i64 = 3;
+
+#[derive(Deserialize)]
+struct BeatmapVersion {
+    // v3 and later
+    #[serde(default)]
+    version: Option<String>,
+    #[serde(rename = "_version", default)]
+    v2_version: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct V2Beatmap {
+    #[serde(rename = "_notes", default)]
+    notes: Vec<V2Note>,
+    #[serde(rename = "_obstacles", default)]
+    obstacles: Vec<V2Obstacle>,
+}
+#[derive(Deserialize)]
+struct V2Note {
+    #[serde(rename = "_time")]
+    time: f64,
+    #[serde(rename = "_lineIndex")]
+    line_index: i64,
+    #[serde(rename = "_lineLayer")]
+    line_layer: i64,
+    #[serde(rename = "_type")]
+    note_type: i64,
+    #[serde(rename = "_cutDirection")]
+    cut_direction: i64,
+}
+#[derive(Deserialize)]
+struct V2Obstacle {
+    #[serde(rename = "_time")]
+    time: f64,
+    #[serde(rename = "_lineIndex")]
+    line_index: i64,
+    #[serde(rename = "_type")]
+    obstacle_type: i64,
+    #[serde(rename = "_duration")]
+    duration: f64,
+    #[serde(rename = "_width")]
+    width: i64,
+}
+
+// v3 leaves out fields that are zero
+#[derive(Deserialize)]
+struct V3Beatmap {
+    #[serde(rename = "colorNotes", default)]
+    color_notes: Vec<V3ColorNote>,
+    #[serde(rename = "bombNotes", default)]
+    bomb_notes: Vec<V3BombNote>,
+    #[serde(default)]
+    obstacles: Vec<V3Obstacle>,
+}
+#[derive(Deserialize)]
+struct V3ColorNote {
+    #[serde(default)]
+    b: f64,
+    #[serde(default)]
+    x: i64,
+    #[serde(default)]
+    y: i64,
+    #[serde(default)]
+    c: i64,
+    #[serde(default)]
+    d: i64,
+}
+#[derive(Deserialize)]
+struct V3BombNote {
+    #[serde(default)]
+    b: f64,
+    #[serde(default)]
+    x: i64,
+    #[serde(default)]
+    y: i64,
+}
+#[derive(Deserialize)]
+struct V3Obstacle {
+    #[serde(default)]
+    b: f64,
+    #[serde(default)]
+    x: i64,
+    #[serde(default)]
+    y: i64,
+    #[serde(default)]
+    d: f64,
+    #[serde(default)]
+    w: i64,
+    #[serde(default)]
+    h: i64,
+}
+
+/// Parse a beatmap dat, which may be v2 or v3 of the format
+pub fn parse_beatmap(dat: &[u8]) -> Result<Beatmap> {
+    let version: BeatmapVersion = serde_json::from_slice(dat).context("failed to parse beatmap")?;
+    match (version.version, version.v2_version) {
+        (Some(version), _) if version.starts_with("3.") => {
+            let beatmap: V3Beatmap = serde_json::from_slice(dat).context("failed to parse v3 beatmap")?;
+            Ok(Beatmap {
+                version,
+                notes: beatmap.color_notes.into_iter()
+                    .map(|n| Note { time: n.b, line_index: n.x, line_layer: n.y, color: n.c, cut_direction: n.d })
+                    .collect(),
+                bombs: beatmap.bomb_notes.into_iter()
+                    .map(|b| Bomb { time: b.b, line_index: b.x, line_layer: b.y })
+                    .collect(),
+                obstacles: beatmap.obstacles.into_iter()
+                    .map(|o| Obstacle { time: o.b, line_index: o.x, line_layer: o.y, duration: o.d, width: o.w, height: o.h })
+                    .collect(),
+            })
+        },
+        (Some(version), _) => bail!("unsupported beatmap version {}", version),
+        // Very old maps have no version at all
+        (None, version) => {
+            let beatmap: V2Beatmap = serde_json::from_slice(dat).context("failed to parse v2 beatmap")?;
+            let (bombs, notes): (Vec<_>, Vec<_>) = beatmap.notes.into_iter().partition(|n| n.note_type == V2_BOMB_TYPE);
+            Ok(Beatmap {
+                version: version.unwrap_or_else(|| "2.0.0".to_owned()),
+                notes: notes.into_iter()
+                    .map(|n| Note { time: n.time, line_index: n.line_index, line_layer: n.line_layer, color: n.note_type, cut_direction: n.cut_direction })
+                    .collect(),
+                bombs: bombs.into_iter()
+                    .map(|b| Bomb { time: b.time, line_index: b.line_index, line_layer: b.line_layer })
+                    .collect(),
+                obstacles: beatmap.obstacles.into_iter()
+                    .map(|o| {
+                        let (line_layer, height) = if o.obstacle_type == V2_CROUCH_OBSTACLE_TYPE {
+                            (CROUCH_OBSTACLE_LAYER, CROUCH_OBSTACLE_HEIGHT)
+                        } else {
+                            (0, FULL_OBSTACLE_HEIGHT)
+                        };
+                        Obstacle { time: o.time, line_index: o.line_index, line_layer, duration: o.duration, width: o.width, height }
+                    })
+                    .collect(),
+            })
+        },
+    }
+}
+
+/// Extract the dat files from a map zip into a tar, with info.dat first, and work out some
+/// [`ExtraMeta`] from the zip and the song inside it
+pub fn zip_to_dats_tar(zipdata: &[u8]) -> Result<(Vec<u8>, ExtraMeta)> {
+    let zipreader = io::Cursor::new(zipdata);
+
+    let mut infodat: Option<(String, InfoDat)> = None;
+    let mut zip = zip::ZipArchive::new(zipreader).context("failed to load zip")?;
+    for zip_index in 0..zip.len() {
+        let entry = zip.by_index(zip_index).context("failed to get entry from zip")?;
+        let name_bytes = entry.name_raw();
+        if name_bytes.eq_ignore_ascii_case(b"info.dat") {
+            if infodat.is_some() {
+                bail!("multiple info.dat candidates")
+            }
+            infodat = Some((
+                String::from_utf8(name_bytes.to_owned()).expect("info.dat name not ascii"),
+                serde_json::from_reader(entry).context("failed to parse info.dat")?,
+            ));
+            break
+        }
+    }
+
+    let (infodat_name, infodat) = infodat.ok_or_else(|| anyhow!("no info.dat found in zip"))?;
+    let mut dat_names: Vec<_> = infodat.difficulty_beatmap_sets.into_iter()
+        .flat_map(|ds| ds.difficulty_beatmaps)
+        .map(|db| db.beatmap_filename)
+        .collect();
+    // Put info.dat at the front, sort and dedup the rest
+    dat_names.sort();
+    dat_names.dedup();
+    dat_names.reverse();
+    dat_names.push(infodat_name);
+    dat_names.reverse();
+
+    debug!("Got dat names: {:?}", dat_names);
+
+    let mut tar = tar::Builder::new(vec![]);
+    for dat_name in dat_names {
+        if !dat_name.is_ascii() {
+            bail!("non-ascii dat name")
+        }
+
+        let mut dat = None;
+        // This nested loop isn't ideal, but `by_name` take a str and we want to avoid decoding as utf8
+        for zip_index in 0..zip.len() {
+            let mut entry = zip.by_index(zip_index).context("failed to get candidate dat entry from zip")?;
+            if entry.name_raw() == dat_name.as_bytes() {
+                if dat.is_some() {
+                    bail!("duplicate entry for dat")
+                }
+                let mut data = vec![];
+                entry.read_to_end(&mut data).context("failed to read dat from zip")?;
+                dat = Some(data)
+            }
+        }
+        let dat = dat.ok_or_else(|| anyhow!("failed to get dat out of zip"))?;
+
+        let mut header = tar::Header::new_old();
+        header.set_path(dat_name).expect("failed to set path");
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(dat.len().try_into().expect("dat length conversion failed"));
+        header.set_cksum();
+        tar.append(&header, &*dat).expect("failed to append data to tar");
+    }
+    let tardata = tar.into_inner().expect("failed to finish tar");
+
+    let song = song_info_from_zip(&infodat.song_filename, zip);
+    let zip_size = zipdata.len().try_into().expect("zip size too big");
+    let extra_meta = ExtraMeta {
+        song_duration: song.info.as_ref().map(|i| i.duration),
+        song_size: song.size,
+        zip_size,
+        song_codec: song.codec,
+        song_sample_rate: song.info.as_ref().map(|i| i.sample_rate),
+        song_channels: song.info.as_ref().map(|i| i.channels),
+        song_error: song.error,
+    };
+
+    Ok((tardata, extra_meta))
+}
+
+struct SongInfo {
+    size: Option<u32>,
+    codec: Option<audio::AudioCodec>,
+    info: Option<audio::AudioInfo>,
+    error: Option<String>,
+}
+
+fn song_info_from_zip(song_name: &str, mut zip: zip::ZipArchive<impl Read + io::Seek>) -> SongInfo {
+    let mut song = SongInfo { size: None, codec: None, info: None, error: None };
+    macro_rules! try_return {
+        ($e:expr) => {
+            match $e {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("error getting song info: {}", e);
+                    song.error = Some(e.to_string());
+                    return song
+                },
+            }
+        };
+    }
+    let mut entry = try_return!(zip.by_name(song_name).context("failed to find song in zip"));
+    let mut songdata = vec![];
+    try_return!(entry.read_to_end(&mut songdata).context("failed to read song from zip"));
+    song.size = Some(songdata.len().try_into().expect("song size too big"));
+    song.codec = Some(audio::detect_codec(&songdata));
+    song.info = Some(try_return!(audio::probe(&songdata)));
+    song
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(dat: serde_json::Value) -> serde_json::Value {
+        serde_json::to_value(parse_beatmap(&serde_json::to_vec(&dat).unwrap()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn beatmap_v2() {
+        let beatmap = parse(json!({
+            "_version": "2.2.0",
+            "_notes": [
+                {"_time": 1.5, "_lineIndex": 1, "_lineLayer": 0, "_type": 0, "_cutDirection": 1},
+                {"_time": 2, "_lineIndex": 2, "_lineLayer": 2, "_type": 3, "_cutDirection": 0},
+                {"_time": 2, "_lineIndex": 3, "_lineLayer": 1, "_type": 1, "_cutDirection": 8},
+            ],
+            "_obstacles": [
+                {"_time": 4, "_lineIndex": 0, "_type": 0, "_duration": 1.5, "_width": 1},
+                {"_time": 8, "_lineIndex": 0, "_type": 1, "_duration": 2, "_width": 4},
+            ],
+            "_events": [],
+        }));
+        assert_eq!(beatmap, json!({
+            "version": "2.2.0",
+            "notes": [
+                {"time": 1.5, "line_index": 1, "line_layer": 0, "color": 0, "cut_direction": 1},
+                {"time": 2.0, "line_index": 3, "line_layer": 1, "color": 1, "cut_direction": 8},
+            ],
+            "bombs": [
+                {"time": 2.0, "line_index": 2, "line_layer": 2},
+            ],
+            "obstacles": [
+                {"time": 4.0, "line_index": 0, "line_layer": 0, "duration": 1.5, "width": 1, "height": 5},
+                // Crouch walls cover the top two layers
+                {"time": 8.0, "line_index": 0, "line_layer": 2, "duration": 2.0, "width": 4, "height": 3},
+            ],
+        }));
+    }
+
+    #[test]
+    fn beatmap_v2_unversioned() {
+        let beatmap = parse(json!({"_notes": [], "_obstacles": []}));
+        assert_eq!(beatmap, json!({"version": "2.0.0", "notes": [], "bombs": [], "obstacles": []}));
+    }
+
+    #[test]
+    fn beatmap_v3() {
+        let beatmap = parse(json!({
+            "version": "3.2.0",
+            "colorNotes": [
+                {"b": 1.5, "x": 1, "c": 0, "d": 1},
+                {"b": 2, "x": 3, "y": 1, "c": 1, "d": 8, "a": 0},
+            ],
+            "bombNotes": [{"b": 2, "x": 2, "y": 2}],
+            "obstacles": [{"b": 8, "y": 2, "d": 2, "w": 4, "h": 3}],
+            "sliders": [],
+        }));
+        // Fields left out are zero
+        assert_eq!(beatmap, json!({
+            "version": "3.2.0",
+            "notes": [
+                {"time": 1.5, "line_index": 1, "line_layer": 0, "color": 0, "cut_direction": 1},
+                {"time": 2.0, "line_index": 3, "line_layer": 1, "color": 1, "cut_direction": 8},
+            ],
+            "bombs": [
+                {"time": 2.0, "line_index": 2, "line_layer": 2},
+            ],
+            "obstacles": [
+                {"time": 8.0, "line_index": 0, "line_layer": 2, "duration": 2.0, "width": 4, "height": 3},
+            ],
+        }));
+    }
+
+    #[test]
+    fn beatmap_unsupported_version() {
+        let err = parse_beatmap(br#"{"version": "4.0.0", "colorNotes": []}"#).unwrap_err();
+        assert_eq!(err.to_string(), "unsupported beatmap version 4.0.0");
+        assert!(parse_beatmap(b"not json").is_err());
+    }
+}
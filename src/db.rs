@@ -0,0 +1,794 @@
+//! All access to the database goes through here. Nothing in this module panics on database
+//! errors - they're returned as a [`DbError`] so callers can decide whether to retry, skip or abort.
+use async_std::task;
+use chrono::Utc;
+use log::warn;
+use sqlx::prelude::*;
+use sqlx::{query, query_as};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::str;
+use std::thread;
+use std::time;
+
+use super::migrations;
+use super::models::*;
+use super::search::{Filter, FilterOp, FilterValue, Sort};
+
+pub type SqliteConnection = sqlx::sqlite::SqlitePool;
+
+pub type DbResult<T> = Result<T, DbError>;
+
+#[derive(Debug)]
+pub enum DbError {
+    /// A row that was expected to exist didn't
+    NotFound,
+    /// A uniqueness, foreign key or check constraint was violated
+    Constraint(sqlx::Error),
+    /// The database was locked for longer than the busy timeout - worth retrying
+    Busy(sqlx::Error),
+    /// Data in the database couldn't be interpreted
+    Corrupt(String),
+    /// The database schema isn't the version we expect
+    SchemaVersion { current: i64, expected: i64 },
+    /// DATABASE_URL isn't set, so there's no database to connect to
+    NoDatabaseUrl(env::VarError),
+    /// Some other error from the database
+    Sqlx(sqlx::Error),
+}
+
+impl DbError {
+    pub fn is_busy(&self) -> bool {
+        matches!(self, DbError::Busy(_))
+    }
+}
+
+// https://www.sqlite.org/rescode.html - sqlx reports extended codes, the primary code is the low byte
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+const SQLITE_CORRUPT: i32 = 11;
+const SQLITE_CONSTRAINT: i32 = 19;
+const SQLITE_NOTADB: i32 = 26;
+
+impl From<sqlx::Error> for DbError {
+    fn from(e: sqlx::Error) -> Self {
+        let code = match &e {
+            sqlx::Error::RowNotFound => return DbError::NotFound,
+            sqlx::Error::Decode(de) => return DbError::Corrupt(de.to_string()),
+            sqlx::Error::Database(dbe) => dbe.code().and_then(|c| c.parse::<i32>().ok()),
+            _ => None,
+        };
+        match code.map(|c| c & 0xff) {
+            Some(SQLITE_BUSY) |
+            Some(SQLITE_LOCKED) => DbError::Busy(e),
+            Some(SQLITE_CONSTRAINT) => DbError::Constraint(e),
+            Some(SQLITE_CORRUPT) |
+            Some(SQLITE_NOTADB) => DbError::Corrupt(e.to_string()),
+            _ => DbError::Sqlx(e),
+        }
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbError::NotFound => write!(f, "row not found"),
+            DbError::Constraint(e) => write!(f, "constraint violated: {}", e),
+            DbError::Busy(e) => write!(f, "database busy: {}", e),
+            DbError::Corrupt(msg) => write!(f, "corrupt data: {}", msg),
+            DbError::SchemaVersion { current, expected } =>
+                write!(f, "database is at schema version {} but this binary requires version {} - run the migrate command", current, expected),
+            DbError::NoDatabaseUrl(e) => write!(f, "DATABASE_URL must be set: {}", e),
+            DbError::Sqlx(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl Error for DbError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DbError::Constraint(e) |
+            DbError::Busy(e) |
+            DbError::Sqlx(e) => Some(e),
+            DbError::NoDatabaseUrl(e) => Some(e),
+            DbError::NotFound |
+            DbError::Corrupt(_) |
+            DbError::SchemaVersion { .. } => None,
+        }
+    }
+}
+
+const BUSY_RETRIES: u32 = 5;
+const BUSY_RETRY_PAUSE: time::Duration = time::Duration::from_secs(1);
+
+/// Run a database operation, retrying with backoff if the database is busy
+pub fn retry_busy<T>(mut f: impl FnMut() -> DbResult<T>) -> DbResult<T> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(e) if e.is_busy() && attempt < BUSY_RETRIES => {
+                attempt += 1;
+                warn!("database busy (attempt {}/{}), retrying: {}", attempt, BUSY_RETRIES, e);
+                thread::sleep(BUSY_RETRY_PAUSE * attempt)
+            },
+            res => return res,
+        }
+    }
+}
+
+fn check_rows(rows_affected: u64, expected: u64, what: &str) -> DbResult<()> {
+    if rows_affected != expected {
+        return Err(DbError::Corrupt(format!("{} affected {} rows, expected {}", what, rows_affected, expected)))
+    }
+    Ok(())
+}
+
+/// Connect to the database, refusing to continue if it isn't at the expected schema version
+pub fn establish_connection() -> DbResult<SqliteConnection> {
+    let conn = connect()?;
+    let current = migrations::current_version(&conn)?;
+    let expected = migrations::latest_version();
+    if current != expected {
+        return Err(DbError::SchemaVersion { current, expected })
+    }
+    Ok(conn)
+}
+
+/// Connect to the database without checking the schema version - only for use by migrations
+pub fn connect() -> DbResult<SqliteConnection> {
+    let database_url = env::var("DATABASE_URL").map_err(DbError::NoDatabaseUrl)?;
+    // We don't actually use this as a pool (yet) - it's just so we can pass it as a reference!
+    let conn = task::block_on(sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .min_connections(1)
+        .after_connect(|conn| Box::pin(async move {
+            conn.execute("
+                PRAGMA journal_mode = WAL;   -- better write-concurrency
+                PRAGMA synchronous = NORMAL; -- fsync only in critical moments
+                PRAGMA busy_timeout = 250;   -- sleep if the database is busy
+                PRAGMA foreign_keys = ON;    -- enforce foreign keys
+            ").await?;
+            Ok(())
+        }))
+        .connect(&database_url))?;
+
+    task::block_on(conn.execute("
+        PRAGMA wal_checkpoint(TRUNCATE); -- free some space by truncating possibly massive WAL files from the last run.
+    "))?;
+
+    Ok(conn)
+}
+
+/// Every key we know about, deleted or not
+pub fn all_song_keys(conn: &SqliteConnection) -> DbResult<Vec<i64>> {
+    let results = task::block_on(query!("SELECT key FROM tSong").fetch_all(conn))?;
+    Ok(results.into_iter().map(|res| res.key).collect())
+}
+
+pub fn deleted_song_keys(conn: &SqliteConnection) -> DbResult<Vec<i64>> {
+    let results = task::block_on(query!("SELECT key FROM tSong WHERE deleted = true").fetch_all(conn))?;
+    Ok(results.into_iter().map(|res| res.key).collect())
+}
+
+/// Key and hash of every non-deleted song with metadata
+pub fn live_songs(conn: &SqliteConnection) -> DbResult<Vec<(i64, String)>> {
+    let results = task::block_on(
+        query!("SELECT s.key, sm.hash FROM tSong s, tSongMeta sm WHERE s.deleted = false AND s.key = sm.key").fetch_all(conn)
+    )?;
+    Ok(results.into_iter().map(|res| (res.key, res.hash)).collect())
+}
+
+/// Key and hash of every non-deleted song with downloaded data
+pub fn songs_with_data(conn: &SqliteConnection) -> DbResult<Vec<(i64, String)>> {
+    let results = task::block_on(
+        query!("SELECT s.key, sm.hash FROM tSong s, tSongMeta sm, tSongData sd WHERE s.deleted = false AND s.key = sm.key AND sm.hash = sd.hash").fetch_all(conn)
+    )?;
+    Ok(results.into_iter().map(|res| (res.key, res.hash)).collect())
+}
+
+/// Key, hash and BeatSaver JSON of the most recent non-deleted songs with downloaded data
+pub fn latest_songs_with_data(conn: &SqliteConnection, limit: i64) -> DbResult<Vec<(i64, String, Vec<u8>)>> {
+    let results = task::block_on(query!("
+        SELECT s.key, sm.hash, sm.bsmeta
+        FROM tSong s, tSongMeta sm, tSongData sd
+        WHERE
+            s.deleted = false AND
+            s.key = sm.key AND
+            sm.hash = sd.hash
+        ORDER BY s.key DESC
+        LIMIT ?
+    ", limit).fetch_all(conn))?;
+    Ok(results.into_iter().map(|res| (res.key, res.hash, res.bsmeta)).collect())
+}
+
+/// Key, hash and BeatSaver JSON of every non-deleted song we don't have data for
+pub fn songs_without_data(conn: &SqliteConnection) -> DbResult<Vec<(i64, String, Vec<u8>)>> {
+    let results = task::block_on(query!("
+        SELECT s.key, sm.hash, sm.bsmeta
+        FROM tSong s
+            INNER JOIN tSongMeta sm ON s.key = sm.key
+            LEFT OUTER JOIN tSongData sd ON sm.hash = sd.hash
+        WHERE s.deleted = false AND sd.hash IS NULL
+    ").fetch_all(conn))?;
+    Ok(results.into_iter().map(|res| (res.key, res.hash, res.bsmeta)).collect())
+}
+
+/// A song with whatever we know about it, for exporting. Deleted songs and songs we haven't
+/// downloaded yet won't have everything.
+pub struct ExportSong {
+    pub key: i64,
+    pub deleted: bool,
+    pub tstamp: i64,
+    pub hash: Option<String>,
+    pub bsmeta: Option<Vec<u8>>,
+    pub extra_meta: Option<Vec<u8>>,
+}
+
+/// Songs with keys in the (inclusive) range, optionally restricted by deletion state, in key order
+pub fn export_songs(conn: &SqliteConnection, min_key: i64, max_key: i64, deleted: Option<bool>) -> DbResult<Vec<ExportSong>> {
+    // query_as! can't tell these columns are nullable because of the outer joins
+    let results: Vec<(i64, bool, i64, Option<String>, Option<Vec<u8>>, Option<Vec<u8>>)> = task::block_on(sqlx::query_as("
+        SELECT s.key, s.deleted, s.tstamp, sm.hash, sm.bsmeta, sd.extra_meta
+        FROM tSong s
+            LEFT OUTER JOIN tSongMeta sm ON s.key = sm.key
+            LEFT OUTER JOIN tSongData sd ON sm.hash = sd.hash
+        WHERE s.key >= ? AND s.key <= ? AND (? IS NULL OR s.deleted = ?)
+        ORDER BY s.key
+    ").bind(min_key).bind(max_key).bind(deleted).bind(deleted).fetch_all(conn))?;
+    Ok(results.into_iter()
+        .map(|(key, deleted, tstamp, hash, bsmeta, extra_meta)| ExportSong { key, deleted, tstamp, hash, bsmeta, extra_meta })
+        .collect())
+}
+
+pub fn get_song_meta(conn: &SqliteConnection, song_key: i64) -> DbResult<Option<SongMeta>> {
+    Ok(task::block_on(
+        query_as!(SongMeta, "SELECT * FROM tSongMeta WHERE key = ?", song_key)
+            .fetch_optional(conn)
+    )?)
+}
+
+/// The BeatSaver metadata for a song by hash - if the same map was uploaded more than once, the
+/// latest upload
+pub fn get_bsmeta_by_hash(conn: &SqliteConnection, hash: &str) -> DbResult<Option<Vec<u8>>> {
+    let res = task::block_on(
+        query!("SELECT bsmeta FROM tSongMeta WHERE hash = ? ORDER BY key DESC LIMIT 1", hash)
+            .fetch_optional(conn)
+    )?;
+    Ok(res.map(|res| res.bsmeta))
+}
+
+// TODO: should only need to pass a &str here but sqlx 0.4 has a 'static bound and we can't upgrade
+// to 0.5 (see Cargo.toml)
+pub fn upsert_song(conn: &SqliteConnection, key: i64, hash_and_meta: Option<(String, Vec<u8>)>) -> DbResult<()> {
+    let tstamp = Utc::now().timestamp_millis();
+    task::block_on(async move {
+        let mut conn = conn.acquire().await?;
+        conn.transaction::<_, _, DbError>(move |conn| Box::pin(async move {
+            let deleted = hash_and_meta.is_none();
+            let res = query!("
+                INSERT INTO tSong (key, deleted, tstamp) VALUES (?, ?, ?)
+                ON CONFLICT (key) DO UPDATE SET deleted=excluded.deleted, tstamp=excluded.tstamp
+            ", key, deleted, tstamp)
+                .execute(&mut *conn).await?;
+            check_rows(res.rows_affected(), 1, &format!("upsert song {}", key))?;
+            if let Some((hash, bsmeta)) = hash_and_meta {
+                let res = query!("
+                    INSERT INTO tSongMeta (key, hash, bsmeta) VALUES (?, ?, ?)
+                    ON CONFLICT (key) DO UPDATE SET hash=excluded.hash, bsmeta=excluded.bsmeta
+                ", key, hash, bsmeta)
+                    .execute(&mut *conn).await?;
+                check_rows(res.rows_affected(), 1, &format!("upsert meta {}", key))?;
+            }
+            Ok(())
+        })).await
+    })
+}
+
+pub fn song_data_hashes(conn: &SqliteConnection) -> DbResult<Vec<String>> {
+    let results = task::block_on(query!("SELECT hash FROM tSongData").fetch_all(conn))?;
+    Ok(results.into_iter().map(|res| res.hash).collect())
+}
+
+pub fn get_song_zipdata(conn: &SqliteConnection, hash: &str) -> DbResult<Vec<u8>> {
+    Ok(task::block_on(
+        query!("SELECT zipdata FROM tSongData WHERE hash = ?", hash).fetch_one(conn)
+    )?.zipdata)
+}
+
+// https://github.com/launchbadge/sqlx/issues/328 - for inserting a Song struct
+pub fn set_song_data(conn: &SqliteConnection, hash: String, data: Vec<u8>, extra_meta: ExtraMeta, zipdata: Vec<u8>) -> DbResult<()> {
+    let tstamp = Utc::now().timestamp_millis();
+    let res = task::block_on(
+        query!("INSERT INTO tSongData (hash, data, extra_meta, zipdata, tstamp) VALUES (?, ?, ?, ?, ?)", hash, data, extra_meta, zipdata, tstamp)
+            .execute(conn)
+    )?;
+    check_rows(res.rows_affected(), 1, &format!("insert song data {}", hash))
+}
+
+/// Replace the data derived from the zip of a song
+pub fn update_song_derived_data(conn: &SqliteConnection, hash: String, data: Vec<u8>, extra_meta: ExtraMeta) -> DbResult<()> {
+    let tstamp = Utc::now().timestamp_millis();
+    let res = task::block_on(query!("
+        UPDATE tSongData
+        SET data = ?, extra_meta = ?, tstamp = ?
+        WHERE hash = ?
+    ", data, extra_meta, tstamp, hash).execute(conn))?;
+    match res.rows_affected() {
+        0 => Err(DbError::NotFound),
+        n => check_rows(n, 1, &format!("update song data {}", hash)),
+    }
+}
+
+/// Load the dat files of a song, keyed by filename
+pub fn load_dats_for_analysis(conn: &SqliteConnection, hash: &str) -> DbResult<HashMap<String, Vec<u8>>> {
+    let data = task::block_on(
+        query!("SELECT data FROM tSongData WHERE hash = ?", hash).fetch_one(conn)
+    )?.data;
+
+    let corrupt = |what: &str, e: &dyn fmt::Display| DbError::Corrupt(format!("{} for {}: {}", what, hash, e));
+    let mut ar = tar::Archive::new(&*data);
+    let mut dats = HashMap::new();
+    for entry in ar.entries().map_err(|e| corrupt("failed to parse dat tar", &e))? {
+        let mut entry = entry.map_err(|e| corrupt("failed to decode dat entry", &e))?;
+        let path_bytes = entry.path_bytes();
+        // Standardise for info.dat to always be lowercase
+        let path = if path_bytes.eq_ignore_ascii_case(b"info.dat") {
+            "info.dat"
+        } else {
+            str::from_utf8(&path_bytes).map_err(|e| corrupt("non-utf8 dat name", &e))?
+        }.to_owned();
+        let mut v = vec![];
+        entry.read_to_end(&mut v).map_err(|e| corrupt("failed to read dat", &e))?;
+        if dats.insert(path.clone(), v).is_some() {
+            return Err(corrupt("duplicate dat", &path))
+        }
+    }
+    if dats.is_empty() {
+        return Err(corrupt("no dats", &"empty tar"))
+    }
+
+    Ok(dats)
+}
+
+/// Whether a song has a result from this version of an analysis (0 for built plugins) - results
+/// from other versions are as good as missing
+pub fn has_song_analysis(conn: &SqliteConnection, hash: &str, analysis_name: &str, plugin_version: i64) -> DbResult<bool> {
+    let res = task::block_on(
+        query!("
+            SELECT count(*) as count FROM tSongAnalysis
+            WHERE hash = ? AND analysis_name = ? AND characteristic = '' AND difficulty = '' AND plugin_version = ?
+        ", hash, analysis_name, plugin_version)
+            .fetch_one(conn)
+    )?;
+    Ok(res.count > 0)
+}
+
+/// All map analysis results for a song, as (analysis name, result JSON)
+pub fn get_song_analyses(conn: &SqliteConnection, hash: &str) -> DbResult<Vec<(String, Vec<u8>)>> {
+    let results = task::block_on(
+        query!("
+            SELECT analysis_name, result FROM tSongAnalysis
+            WHERE hash = ? AND characteristic = '' AND difficulty = ''
+        ", hash).fetch_all(conn)
+    )?;
+    Ok(results.into_iter().map(|res| (res.analysis_name, res.result)).collect())
+}
+
+/// All beatmap analysis results for a song, as (analysis name, characteristic, difficulty, result JSON)
+pub fn get_song_beatmap_analyses(conn: &SqliteConnection, hash: &str) -> DbResult<Vec<(String, String, String, Vec<u8>)>> {
+    let results = task::block_on(
+        query!("
+            SELECT analysis_name, characteristic, difficulty, result FROM tSongAnalysis
+            WHERE hash = ? AND characteristic != '' AND difficulty != ''
+            ORDER BY analysis_name, characteristic, difficulty
+        ", hash).fetch_all(conn)
+    )?;
+    Ok(results.into_iter().map(|res| (res.analysis_name, res.characteristic, res.difficulty, res.result)).collect())
+}
+
+/// Store the results of an analysis of a song - the result for the whole map, and the results
+/// for each (characteristic, difficulty) beatmap if the analysis looks at beatmaps. Replaces any
+/// results from other versions of the analysis.
+pub fn insert_song_analysis(conn: &SqliteConnection, hash: String, analysis_name: String, plugin_version: i64, result: Vec<u8>, beatmap_results: Vec<(String, String, Vec<u8>)>) -> DbResult<()> {
+    let tstamp = Utc::now().timestamp_millis();
+    task::block_on(async move {
+        let mut conn = conn.acquire().await?;
+        conn.transaction::<_, _, DbError>(move |conn| Box::pin(async move {
+            query!("DELETE FROM tSongAnalysis WHERE hash = ? AND analysis_name = ?", hash, analysis_name)
+                .execute(&mut *conn).await?;
+            let res = query!("
+                INSERT INTO tSongAnalysis (hash, analysis_name, characteristic, difficulty, result, plugin_version, tstamp)
+                VALUES (?, ?, '', '', ?, ?, ?)
+            ", hash, analysis_name, result, plugin_version, tstamp)
+                .execute(&mut *conn).await?;
+            check_rows(res.rows_affected(), 1, &format!("insert analysis {} {}", analysis_name, hash))?;
+            for (characteristic, difficulty, result) in beatmap_results {
+                let res = query!("
+                    INSERT INTO tSongAnalysis (hash, analysis_name, characteristic, difficulty, result, plugin_version, tstamp)
+                    VALUES (?, ?, ?, ?, ?, ?, ?)
+                ", hash, analysis_name, characteristic, difficulty, result, plugin_version, tstamp)
+                    .execute(&mut *conn).await?;
+                check_rows(res.rows_affected(), 1, &format!("insert analysis {} {} {} {}", analysis_name, hash, characteristic, difficulty))?;
+            }
+            Ok(())
+        })).await
+    })
+}
+
+/// Record the log of the latest run of an analysis on a song, and its error if it failed,
+/// replacing whatever the previous run logged
+pub fn upsert_song_analysis_log(conn: &SqliteConnection, hash: String, analysis_name: String, log: String, error: Option<String>) -> DbResult<()> {
+    let tstamp = Utc::now().timestamp_millis();
+    let res = task::block_on(
+        query!("
+            INSERT INTO tSongAnalysisLog (hash, analysis_name, log, error, tstamp) VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (hash, analysis_name) DO UPDATE SET log=excluded.log, error=excluded.error, tstamp=excluded.tstamp
+        ", hash, analysis_name, log, error, tstamp)
+            .execute(conn)
+    )?;
+    check_rows(res.rows_affected(), 1, &format!("upsert analysis log {} {}", analysis_name, hash))
+}
+
+/// The log of the latest run of an analysis on a song, and its error if it failed
+pub fn get_song_analysis_log(conn: &SqliteConnection, hash: &str, analysis_name: &str) -> DbResult<Option<(String, Option<String>)>> {
+    let res = task::block_on(
+        query!("SELECT log, error FROM tSongAnalysisLog WHERE hash = ? AND analysis_name = ?", hash, analysis_name)
+            .fetch_optional(conn)
+    )?;
+    Ok(res.map(|res| (res.log, res.error)))
+}
+
+/// Forget every result and log of an analysis, so it gets run again. Songs that had a result are
+/// marked as needing pushing to the search indexes again without it. Returns how many results
+/// (map and beatmap) were deleted.
+pub fn delete_song_analyses(conn: &SqliteConnection, analysis_name: String) -> DbResult<u64> {
+    task::block_on(async move {
+        let mut conn = conn.acquire().await?;
+        conn.transaction::<_, _, DbError>(move |conn| Box::pin(async move {
+            // Kept rather than deleted, since deleted songs still need unindexing
+            query!("
+                UPDATE tSearchIndexed SET tstamp = 0 WHERE key IN (
+                    SELECT sm.key FROM tSongMeta sm, tSongAnalysis sa WHERE sm.hash = sa.hash AND sa.analysis_name = ?
+                )
+            ", analysis_name)
+                .execute(&mut *conn).await?;
+            let res = query!("DELETE FROM tSongAnalysis WHERE analysis_name = ?", analysis_name)
+                .execute(&mut *conn).await?;
+            query!("DELETE FROM tSongAnalysisLog WHERE analysis_name = ?", analysis_name)
+                .execute(&mut *conn).await?;
+            Ok(res.rows_affected())
+        })).await
+    })
+}
+
+/// All aggregate analysis results for a song, as (analysis name, result JSON)
+pub fn get_song_aggregates(conn: &SqliteConnection, key: i64) -> DbResult<Vec<(String, Vec<u8>)>> {
+    let results = task::block_on(
+        query!("SELECT analysis_name, result FROM tSongAggregate WHERE key = ?", key).fetch_all(conn)
+    )?;
+    Ok(results.into_iter().map(|res| (res.analysis_name, res.result)).collect())
+}
+
+/// Replace all the results of an aggregate analysis with the results of a new run. Only results
+/// that changed get a new timestamp, and songs that no longer have a result are marked as needing
+/// pushing to the search indexes again. Songs in `unchanged` keep whatever result they had.
+pub fn replace_song_aggregates(conn: &SqliteConnection, analysis_name: String, results: Vec<(i64, Vec<u8>)>, unchanged: Vec<i64>) -> DbResult<()> {
+    let tstamp = Utc::now().timestamp_millis();
+    task::block_on(async move {
+        let mut conn = conn.acquire().await?;
+        conn.transaction::<_, _, DbError>(move |conn| Box::pin(async move {
+            let existing = query!("SELECT key FROM tSongAggregate WHERE analysis_name = ?", analysis_name)
+                .fetch_all(&mut *conn).await?;
+            let new_keys: HashSet<i64> = results.iter().map(|&(key, _)| key).chain(unchanged).collect();
+            for key in existing.into_iter().map(|res| res.key).filter(|key| !new_keys.contains(key)) {
+                let res = query!("DELETE FROM tSongAggregate WHERE key = ? AND analysis_name = ?", key, analysis_name)
+                    .execute(&mut *conn).await?;
+                check_rows(res.rows_affected(), 1, &format!("delete aggregate {} {}", analysis_name, key))?;
+                // Kept rather than deleted, since deleted songs still need unindexing
+                query!("UPDATE tSearchIndexed SET tstamp = 0 WHERE key = ?", key)
+                    .execute(&mut *conn).await?;
+            }
+            for (key, result) in results {
+                // Unchanged results aren't touched, so they don't need pushing to search again
+                query!("
+                    INSERT INTO tSongAggregate (key, analysis_name, result, tstamp) VALUES (?, ?, ?, ?)
+                    ON CONFLICT (key, analysis_name) DO UPDATE SET result=excluded.result, tstamp=excluded.tstamp
+                    WHERE result != excluded.result
+                ", key, analysis_name, result, tstamp)
+                    .execute(&mut *conn).await?;
+            }
+            Ok(())
+        })).await
+    })
+}
+
+/// The name of the index that searches with a backend should go to, if one has ever been built
+pub fn get_active_search_index(conn: &SqliteConnection, backend: &str) -> DbResult<Option<String>> {
+    let res = task::block_on(
+        query!("SELECT index_name FROM tSearchActiveIndex WHERE backend = ?", backend).fetch_optional(conn)
+    )?;
+    Ok(res.map(|res| res.index_name))
+}
+
+pub fn set_active_search_index(conn: &SqliteConnection, backend: &str, index_name: &str) -> DbResult<()> {
+    let res = task::block_on(query!("
+        INSERT INTO tSearchActiveIndex (backend, index_name) VALUES (?, ?)
+        ON CONFLICT (backend) DO UPDATE SET index_name=excluded.index_name
+    ", backend, index_name).execute(conn))?;
+    check_rows(res.rows_affected(), 1, &format!("set active {} search index {}", backend, index_name))
+}
+
+/// Key and hash of every non-deleted song with metadata that either isn't in the search index, or
+/// has had its metadata, data or analyses change since it was pushed
+pub fn songs_to_index(conn: &SqliteConnection, index_name: &str) -> DbResult<Vec<(i64, String)>> {
+    // Changes made in the same millisecond as a sync started may or may not have been seen by it,
+    // so compare with >= and err on the side of pushing again
+    let results = task::block_on(query!("
+        SELECT s.key, sm.hash
+        FROM tSong s
+            INNER JOIN tSongMeta sm ON s.key = sm.key
+            LEFT OUTER JOIN tSongData sd ON sm.hash = sd.hash
+            LEFT OUTER JOIN tSearchIndexed si ON si.index_name = ? AND s.key = si.key
+        WHERE
+            s.deleted = false AND (
+                si.tstamp IS NULL OR
+                s.tstamp >= si.tstamp OR
+                sd.tstamp >= si.tstamp OR
+                EXISTS (SELECT 1 FROM tSongAnalysis sa WHERE sa.hash = sm.hash AND sa.tstamp >= si.tstamp) OR
+                EXISTS (SELECT 1 FROM tSongAggregate sg WHERE sg.key = s.key AND sg.tstamp >= si.tstamp)
+            )
+        ORDER BY s.key
+    ", index_name).fetch_all(conn))?;
+    Ok(results.into_iter().map(|res| (res.key, res.hash)).collect())
+}
+
+/// Keys of songs in the search index that have since been deleted
+pub fn songs_to_unindex(conn: &SqliteConnection, index_name: &str) -> DbResult<Vec<i64>> {
+    let results = task::block_on(query!("
+        SELECT si.key
+        FROM tSearchIndexed si, tSong s
+        WHERE si.index_name = ? AND si.key = s.key AND s.deleted = true
+        ORDER BY si.key
+    ", index_name).fetch_all(conn))?;
+    Ok(results.into_iter().map(|res| res.key).collect())
+}
+
+/// Record that songs were pushed to a search index by a sync that started at `tstamp`
+pub fn mark_search_indexed(conn: &SqliteConnection, index_name: String, keys: Vec<i64>, tstamp: i64) -> DbResult<()> {
+    task::block_on(async move {
+        let mut conn = conn.acquire().await?;
+        conn.transaction::<_, _, DbError>(move |conn| Box::pin(async move {
+            for key in keys {
+                let res = query!("
+                    INSERT INTO tSearchIndexed (index_name, key, tstamp) VALUES (?, ?, ?)
+                    ON CONFLICT (index_name, key) DO UPDATE SET tstamp=excluded.tstamp
+                ", index_name, key, tstamp)
+                    .execute(&mut *conn).await?;
+                check_rows(res.rows_affected(), 1, &format!("mark {} indexed in {}", key, index_name))?;
+            }
+            Ok(())
+        })).await
+    })
+}
+
+/// Record that songs were removed from a search index
+pub fn unmark_search_indexed(conn: &SqliteConnection, index_name: String, keys: Vec<i64>) -> DbResult<()> {
+    task::block_on(async move {
+        let mut conn = conn.acquire().await?;
+        conn.transaction::<_, _, DbError>(move |conn| Box::pin(async move {
+            for key in keys {
+                let res = query!("DELETE FROM tSearchIndexed WHERE index_name = ? AND key = ?", index_name, key)
+                    .execute(&mut *conn).await?;
+                check_rows(res.rows_affected(), 1, &format!("unmark {} indexed in {}", key, index_name))?;
+            }
+            Ok(())
+        })).await
+    })
+}
+
+/// Forget everything about what's in a search index, e.g. once it's been deleted
+pub fn clear_search_indexed(conn: &SqliteConnection, index_name: &str) -> DbResult<()> {
+    task::block_on(
+        query!("DELETE FROM tSearchIndexed WHERE index_name = ?", index_name).execute(conn)
+    )?;
+    Ok(())
+}
+
+/// A song in the embedded search backend
+#[derive(Clone)]
+pub struct SearchDoc {
+    pub key: String,
+    // Searched text
+    pub name: String,
+    pub sub_name: String,
+    pub description: String,
+    pub uploader: String,
+    pub modes: String,
+    /// The whole document, returned from searches
+    pub doc: Vec<u8>,
+}
+
+pub fn upsert_search_docs(conn: &SqliteConnection, index_name: String, docs: Vec<SearchDoc>) -> DbResult<()> {
+    task::block_on(async move {
+        let mut conn = conn.acquire().await?;
+        conn.transaction::<_, _, DbError>(move |conn| Box::pin(async move {
+            for SearchDoc { key, name, sub_name, description, uploader, modes, doc } in docs {
+                let res = query!("
+                    INSERT INTO tSearchDoc (index_name, key, name, sub_name, description, uploader, modes, doc)
+                    VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+                    ON CONFLICT (index_name, key) DO UPDATE SET
+                        name=excluded.name, sub_name=excluded.sub_name, description=excluded.description,
+                        uploader=excluded.uploader, modes=excluded.modes, doc=excluded.doc
+                ", index_name, key, name, sub_name, description, uploader, modes, doc)
+                    .execute(&mut *conn).await?;
+                check_rows(res.rows_affected(), 1, &format!("upsert search doc {} in {}", key, index_name))?;
+            }
+            Ok(())
+        })).await
+    })
+}
+
+/// Remove songs from the embedded search backend - keys that aren't there are ignored
+pub fn delete_search_docs(conn: &SqliteConnection, index_name: String, keys: Vec<String>) -> DbResult<()> {
+    task::block_on(async move {
+        let mut conn = conn.acquire().await?;
+        conn.transaction::<_, _, DbError>(move |conn| Box::pin(async move {
+            for key in keys {
+                query!("DELETE FROM tSearchDoc WHERE index_name = ? AND key = ?", index_name, key)
+                    .execute(&mut *conn).await?;
+            }
+            Ok(())
+        })).await
+    })
+}
+
+pub fn delete_search_index_docs(conn: &SqliteConnection, index_name: &str) -> DbResult<()> {
+    task::block_on(
+        query!("DELETE FROM tSearchDoc WHERE index_name = ?", index_name).execute(conn)
+    )?;
+    Ok(())
+}
+
+/// Documents from the embedded search backend matching an (optional) FTS5 query and all of the
+/// filters. Results are sorted as requested, then best text matches first.
+pub fn search_docs(conn: &SqliteConnection, index_name: &str, fts_query: Option<&str>, filters: &[Filter], sort: Option<&Sort>, limit: i64) -> DbResult<Vec<Vec<u8>>> {
+    // Attributes are checked to be plain identifiers when filters are parsed, but bind the JSON
+    // paths anyway
+    fn json_path(attribute: &str) -> String {
+        format!("$.\"{}\"", attribute)
+    }
+
+    let mut sql = String::from("SELECT d.doc FROM tSearchDoc d ");
+    if fts_query.is_some() {
+        sql.push_str("INNER JOIN tSearchFts f ON d.id = f.rowid WHERE tSearchFts MATCH ? AND ");
+    } else {
+        sql.push_str("WHERE ");
+    }
+    sql.push_str("d.index_name = ? ");
+    // json_each gives a scalar as a single value and an array as its elements, so arrays match if
+    // any element does (like meilisearch) - except for !=, which means no element is equal
+    for filter in filters {
+        let (exists, op) = match filter.op {
+            FilterOp::Ne => ("NOT EXISTS", FilterOp::Eq),
+            op => ("EXISTS", op),
+        };
+        sql.push_str(&format!("AND {} (SELECT 1 FROM json_each(CAST(d.doc AS TEXT), ?) WHERE value {} ?) ", exists, op.as_str()));
+    }
+    let mut order_by = vec![];
+    if let Some(sort) = sort {
+        // SQLite puts NULLs first when ascending, but meilisearch puts documents without the
+        // attribute last either way
+        order_by.push("json_extract(CAST(d.doc AS TEXT), ?) IS NULL".to_owned());
+        order_by.push(format!("json_extract(CAST(d.doc AS TEXT), ?) {}", if sort.descending { "DESC" } else { "ASC" }));
+    }
+    if fts_query.is_some() {
+        order_by.push("f.rank".to_owned());
+    }
+    if !order_by.is_empty() {
+        sql.push_str(&format!("ORDER BY {} ", order_by.join(", ")));
+    }
+    sql.push_str("LIMIT ?");
+
+    // query_as! doesn't understand the virtual table, or dynamic queries
+    let mut query = sqlx::query_as::<_, (Vec<u8>,)>(&sql);
+    if let Some(fts_query) = fts_query {
+        query = query.bind(fts_query);
+    }
+    query = query.bind(index_name);
+    for filter in filters {
+        query = query.bind(json_path(&filter.attribute));
+        // JSON booleans come out of json_each as 0 or 1
+        query = match &filter.value {
+            FilterValue::Bool(b) => query.bind(*b as i64),
+            FilterValue::Number(n) => query.bind(*n),
+            FilterValue::String(s) => query.bind(s.clone()),
+        };
+    }
+    if let Some(sort) = sort {
+        query = query.bind(json_path(&sort.attribute)).bind(json_path(&sort.attribute));
+    }
+    query = query.bind(limit);
+
+    let results = task::block_on(query.fetch_all(conn))?;
+    Ok(results.into_iter().map(|(doc,)| doc).collect())
+}
+
+/// The extra meta of a song, if we have its data
+pub fn get_song_extra_meta(conn: &SqliteConnection, hash: &str) -> DbResult<Option<ExtraMeta>> {
+    let res = task::block_on(
+        query!("SELECT extra_meta FROM tSongData WHERE hash = ?", hash).fetch_optional(conn)
+    )?;
+    res.map(|res| serde_json::from_slice(&res.extra_meta)
+            .map_err(|e| DbError::Corrupt(format!("failed to deserialize extra meta for {}: {}", hash, e))))
+        .transpose()
+}
+
+/// Hashes of non-deleted songs with downloaded data that don't have a fingerprint from this
+/// version of the fingerprinting
+pub fn songs_to_fingerprint(conn: &SqliteConnection, version: i64) -> DbResult<Vec<String>> {
+    let results: Vec<(String,)> = task::block_on(sqlx::query_as("
+        SELECT DISTINCT sm.hash
+        FROM tSong s
+            INNER JOIN tSongMeta sm ON s.key = sm.key
+            INNER JOIN tSongData sd ON sm.hash = sd.hash
+            LEFT OUTER JOIN tSongFingerprint sf ON sm.hash = sf.hash
+        WHERE s.deleted = false AND (sf.hash IS NULL OR sf.version != ?)
+    ").bind(version).fetch_all(conn))?;
+    Ok(results.into_iter().map(|(hash,)| hash).collect())
+}
+
+pub fn set_song_fingerprint(conn: &SqliteConnection, hash: &str, version: i64, fingerprint: Vec<u8>) -> DbResult<()> {
+    let tstamp = Utc::now().timestamp_millis();
+    let res = task::block_on(query!("
+        INSERT INTO tSongFingerprint (hash, version, fingerprint, tstamp) VALUES (?, ?, ?, ?)
+        ON CONFLICT (hash) DO UPDATE SET version=excluded.version, fingerprint=excluded.fingerprint, tstamp=excluded.tstamp
+    ", hash, version, fingerprint, tstamp).execute(conn))?;
+    check_rows(res.rows_affected(), 1, &format!("set fingerprint {}", hash))
+}
+
+/// Key and fingerprint of every non-deleted song with a fingerprint from this version of the
+/// fingerprinting
+pub fn live_song_fingerprints(conn: &SqliteConnection, version: i64) -> DbResult<Vec<(i64, Vec<u8>)>> {
+    let results = task::block_on(query!("
+        SELECT s.key, sf.fingerprint
+        FROM tSong s, tSongMeta sm, tSongFingerprint sf
+        WHERE s.deleted = false AND s.key = sm.key AND sm.hash = sf.hash AND sf.version = ?
+        ORDER BY s.key
+    ", version).fetch_all(conn))?;
+    Ok(results.into_iter().map(|res| (res.key, res.fingerprint)).collect())
+}
+
+/// Replace the pairs found by a previous duplicates run
+pub fn replace_duplicate_pairs(conn: &SqliteConnection, pairs: Vec<DuplicatePair>) -> DbResult<()> {
+    task::block_on(async move {
+        let mut conn = conn.acquire().await?;
+        conn.transaction::<_, _, DbError>(move |conn| Box::pin(async move {
+            query!("DELETE FROM tDuplicatePair").execute(&mut *conn).await?;
+            for pair in pairs {
+                let res = query!("
+                    INSERT INTO tDuplicatePair (key_a, key_b, audio_exact, audio, chart, cover) VALUES (?, ?, ?, ?, ?, ?)
+                ", pair.key_a, pair.key_b, pair.audio_exact, pair.audio, pair.chart, pair.cover)
+                    .execute(&mut *conn).await?;
+                check_rows(res.rows_affected(), 1, &format!("insert duplicate pair {} {}", pair.key_a, pair.key_b))?;
+            }
+            Ok(())
+        })).await
+    })
+}
+
+/// The pairs found by the last duplicates run, optionally just those involving one song
+pub fn get_duplicate_pairs(conn: &SqliteConnection, key: Option<i64>) -> DbResult<Vec<DuplicatePair>> {
+    // query_as! can't tell the score columns are nullable
+    let results: Vec<(i64, i64, bool, Option<f64>, Option<f64>, Option<i64>)> = task::block_on(sqlx::query_as("
+        SELECT key_a, key_b, audio_exact, audio, chart, cover
+        FROM tDuplicatePair
+        WHERE ? IS NULL OR key_a = ? OR key_b = ?
+        ORDER BY key_a, key_b
+    ").bind(key).bind(key).bind(key).fetch_all(conn))?;
+    Ok(results.into_iter()
+        .map(|(key_a, key_b, audio_exact, audio, chart, cover)| DuplicatePair { key_a, key_b, audio_exact, audio, chart, cover })
+        .collect())
+}
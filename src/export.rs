@@ -0,0 +1,271 @@
+//! Flattening the whole dataset into a table, for loading into notebooks, DuckDB and the like
+use anyhow::{Context, Result, bail};
+use log::info;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::str::FromStr;
+
+use super::db::{self, SqliteConnection, retry_busy};
+use super::models::{BeatSaverMap, BeatSaverMapVersionState};
+use super::num_to_key;
+use super::search;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ExportFormat {
+    Jsonl,
+    Csv,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "jsonl" => ExportFormat::Jsonl,
+            "csv" => ExportFormat::Csv,
+            #[cfg(feature = "parquet")]
+            "parquet" => ExportFormat::Parquet,
+            #[cfg(not(feature = "parquet"))]
+            "parquet" => bail!("parquet support was not enabled at build time"),
+            other => bail!("unknown export format {}", other),
+        })
+    }
+}
+
+pub struct ExportFilter {
+    /// Inclusive range of (integer) keys to export
+    pub min_key: i64,
+    pub max_key: i64,
+    /// `Some(d)` to only export songs with deleted = d, `None` for all
+    pub deleted: Option<bool>,
+    /// Only include results from these analyses, `None` for all
+    pub analyses: Option<Vec<String>>,
+}
+
+impl Default for ExportFilter {
+    fn default() -> Self {
+        Self { min_key: 0, max_key: i64::MAX, deleted: Some(false), analyses: None }
+    }
+}
+
+// Columns that every row has (possibly as null), in the order they're output - analysis columns
+// come after these, sorted
+const BASE_COLUMNS: &[&str] = &[
+    "key", "key_num", "deleted", "updated_at_tstamp", "hash",
+    "name", "sub_name", "uploader", "uploaded_at_tstamp", "upvotes", "downvotes", "modes", "description",
+    "song_duration", "song_size", "zip_size", "song_codec", "song_sample_rate", "song_channels", "song_error",
+];
+
+type Row = BTreeMap<String, Value>;
+
+/// Write every song matching the filter as a row to `out`, returning the number of rows written
+pub fn export(conn: &SqliteConnection, filter: &ExportFilter, format: ExportFormat, out: impl Write + Send) -> Result<usize> {
+    let songs = retry_busy(|| db::export_songs(conn, filter.min_key, filter.max_key, filter.deleted))
+        .context("failed to load songs")?;
+    info!("Exporting {} songs", songs.len());
+
+    let mut rows = Vec::with_capacity(songs.len());
+    let mut analysis_columns = BTreeSet::new();
+    for song in songs {
+        let mut row = Row::new();
+        row.insert("key".to_owned(), num_to_key(song.key).into());
+        row.insert("key_num".to_owned(), song.key.into());
+        row.insert("deleted".to_owned(), song.deleted.into());
+        row.insert("updated_at_tstamp".to_owned(), song.tstamp.into());
+        row.insert("hash".to_owned(), song.hash.clone().map_or(Value::Null, Value::from));
+
+        if let Some(bsmeta) = &song.bsmeta {
+            let bsmeta: BeatSaverMap = serde_json::from_slice(bsmeta)
+                .with_context(|| format!("failed to deserialize bsmeta for {}", num_to_key(song.key)))?;
+            // Don't use current_version, since it asserts there's exactly one published version
+            let modes = bsmeta.versions.iter()
+                .find(|v| v.state == BeatSaverMapVersionState::Published)
+                .map(|v| {
+                    let modes: BTreeSet<_> = v.diffs.iter().map(|d| d.characteristic.as_str()).collect();
+                    modes.into_iter().collect::<Vec<_>>().join(",")
+                });
+            row.insert("name".to_owned(), bsmeta.metadata.song_name.into());
+            row.insert("sub_name".to_owned(), bsmeta.metadata.song_sub_name.into());
+            row.insert("uploader".to_owned(), bsmeta.uploader.name.into());
+            row.insert("uploaded_at_tstamp".to_owned(), bsmeta.uploaded.timestamp().into());
+            row.insert("upvotes".to_owned(), bsmeta.stats.upvotes.into());
+            row.insert("downvotes".to_owned(), bsmeta.stats.downvotes.into());
+            row.insert("modes".to_owned(), modes.map_or(Value::Null, Value::from));
+            row.insert("description".to_owned(), bsmeta.description.into());
+        }
+
+        if let Some(extra_meta) = &song.extra_meta {
+            // Go via Value rather than ExtraMeta so new fields don't need adding here
+            let extra_meta: BTreeMap<String, Value> = serde_json::from_slice(extra_meta)
+                .with_context(|| format!("failed to deserialize extra meta for {}", num_to_key(song.key)))?;
+            row.extend(extra_meta);
+        }
+
+        if let Some(hash) = &song.hash {
+            let analyses = retry_busy(|| db::get_song_analyses(conn, hash)).context("failed to load analyses")?
+                .into_iter().map(|(analysis_name, result)| (analysis_name, None, result));
+            let beatmap_analyses = retry_busy(|| db::get_song_beatmap_analyses(conn, hash)).context("failed to load beatmap analyses")?
+                .into_iter().map(|(analysis_name, characteristic, difficulty, result)| (analysis_name, Some((characteristic, difficulty)), result));
+            let aggregates = retry_busy(|| db::get_song_aggregates(conn, song.key)).context("failed to load aggregates")?
+                .into_iter().map(|(analysis_name, result)| (analysis_name, None, result));
+            for (analysis_name, beatmap, result) in analyses.chain(beatmap_analyses).chain(aggregates) {
+                if let Some(wanted) = &filter.analyses {
+                    if !wanted.contains(&analysis_name) {
+                        continue
+                    }
+                }
+                let result: BTreeMap<String, Value> = serde_json::from_slice(&result)
+                    .with_context(|| format!("failed to deserialize analysis {} for {}", analysis_name, hash))?;
+                let beatmap = beatmap.as_ref().map(|(c, d)| (c.as_str(), d.as_str()));
+                for (k, v) in result {
+                    // Same naming as the search index
+                    let column = search::analysis_attribute(&analysis_name, beatmap, &k);
+                    analysis_columns.insert(column.clone());
+                    row.insert(column, v);
+                }
+            }
+        }
+
+        rows.push(row)
+    }
+
+    let mut columns: Vec<String> = BASE_COLUMNS.iter().map(|&c| c.to_owned()).collect();
+    // Pick up anything new from extra meta
+    let known: BTreeSet<_> = columns.iter().cloned().chain(analysis_columns.iter().cloned()).collect();
+    let extra_columns: BTreeSet<_> = rows.iter().flat_map(|r| r.keys()).filter(|k| !known.contains(*k)).cloned().collect();
+    columns.extend(extra_columns);
+    columns.extend(analysis_columns);
+
+    match format {
+        ExportFormat::Jsonl => write_jsonl(&rows, out)?,
+        ExportFormat::Csv => write_csv(&columns, &rows, out)?,
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => write_parquet(&columns, &rows, out)?,
+    }
+    Ok(rows.len())
+}
+
+fn write_jsonl(rows: &[Row], mut out: impl Write) -> Result<()> {
+    for row in rows {
+        serde_json::to_writer(&mut out, row).context("failed to write row")?;
+        out.write_all(b"\n").context("failed to write row")?;
+    }
+    out.flush().context("failed to flush output")
+}
+
+fn write_csv(columns: &[String], rows: &[Row], out: impl Write) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(columns).context("failed to write csv header")?;
+    for row in rows {
+        let record = columns.iter().map(|c| match row.get(c) {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(s)) => s.clone(),
+            Some(v) => v.to_string(),
+        });
+        writer.write_record(record).context("failed to write csv row")?;
+    }
+    writer.flush().context("failed to flush output")
+}
+
+#[cfg(feature = "parquet")]
+fn write_parquet(columns: &[String], rows: &[Row], out: impl Write + Send) -> Result<()> {
+    use parquet::basic::{LogicalType, Repetition, Type as PhysicalType};
+    use parquet::column::writer::ColumnWriter;
+    use parquet::data_type::ByteArray;
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::types::Type;
+    use std::sync::Arc;
+
+    const ROW_GROUP_SIZE: usize = 10000;
+
+    #[derive(Clone, Copy)]
+    enum Kind { Bool, Int, Float, Str, Json }
+
+    // Columns get the narrowest type that fits every non-null value - arrays and objects are JSON,
+    // anything mixed becomes a string, with non-string values JSON encoded
+    let kinds: Vec<Kind> = columns.iter().map(|c| {
+        let mut kind = None;
+        for v in rows.iter().filter_map(|r| r.get(c)) {
+            let this = match v {
+                Value::Null => continue,
+                Value::Bool(_) => Kind::Bool,
+                Value::Number(n) if n.is_i64() => Kind::Int,
+                Value::Number(_) => Kind::Float,
+                Value::String(_) => Kind::Str,
+                Value::Array(_) | Value::Object(_) => Kind::Json,
+            };
+            kind = Some(match (kind, this) {
+                (None, k) => k,
+                (Some(Kind::Bool), Kind::Bool) => Kind::Bool,
+                (Some(Kind::Int), Kind::Int) => Kind::Int,
+                (Some(Kind::Int), Kind::Float) |
+                (Some(Kind::Float), Kind::Int) |
+                (Some(Kind::Float), Kind::Float) => Kind::Float,
+                (Some(Kind::Json), Kind::Json) => Kind::Json,
+                _ => Kind::Str,
+            })
+        }
+        kind.unwrap_or(Kind::Str)
+    }).collect();
+
+    let fields = columns.iter().zip(&kinds).map(|(c, kind)| {
+        let builder = match kind {
+            Kind::Bool => Type::primitive_type_builder(c, PhysicalType::BOOLEAN),
+            Kind::Int => Type::primitive_type_builder(c, PhysicalType::INT64),
+            Kind::Float => Type::primitive_type_builder(c, PhysicalType::DOUBLE),
+            Kind::Str => Type::primitive_type_builder(c, PhysicalType::BYTE_ARRAY)
+                .with_logical_type(Some(LogicalType::String)),
+            Kind::Json => Type::primitive_type_builder(c, PhysicalType::BYTE_ARRAY)
+                .with_logical_type(Some(LogicalType::Json)),
+        };
+        Ok(Arc::new(builder.with_repetition(Repetition::OPTIONAL).build()?))
+    }).collect::<Result<Vec<_>>>()?;
+    let schema = Arc::new(Type::group_type_builder("song").with_fields(fields).build()?);
+    let props = Arc::new(WriterProperties::builder().build());
+    let mut writer = SerializedFileWriter::new(out, schema, props).context("failed to create parquet writer")?;
+
+    for chunk in rows.chunks(ROW_GROUP_SIZE) {
+        let mut row_group = writer.next_row_group()?;
+        for (c, kind) in columns.iter().zip(&kinds) {
+            let values: Vec<&Value> = chunk.iter()
+                .map(|r| r.get(c).unwrap_or(&Value::Null))
+                .collect();
+            // Definition level 1 means present, 0 means null
+            let def_levels: Vec<i16> = values.iter().map(|v| if v.is_null() { 0 } else { 1 }).collect();
+            let present = values.iter().filter(|v| !v.is_null());
+            let mut column = row_group.next_column()?.expect("fewer parquet columns than schema fields");
+            match (column.untyped(), kind) {
+                (ColumnWriter::BoolColumnWriter(w), Kind::Bool) => {
+                    let vals: Vec<bool> = present.map(|v| v.as_bool().expect("non-bool in bool column")).collect();
+                    w.write_batch(&vals, Some(&def_levels), None)?;
+                },
+                (ColumnWriter::Int64ColumnWriter(w), Kind::Int) => {
+                    let vals: Vec<i64> = present.map(|v| v.as_i64().expect("non-int in int column")).collect();
+                    w.write_batch(&vals, Some(&def_levels), None)?;
+                },
+                (ColumnWriter::DoubleColumnWriter(w), Kind::Float) => {
+                    let vals: Vec<f64> = present.map(|v| v.as_f64().expect("non-number in float column")).collect();
+                    w.write_batch(&vals, Some(&def_levels), None)?;
+                },
+                (ColumnWriter::ByteArrayColumnWriter(w), Kind::Str) |
+                (ColumnWriter::ByteArrayColumnWriter(w), Kind::Json) => {
+                    let vals: Vec<ByteArray> = present
+                        .map(|v| match v {
+                            Value::String(s) => s.as_str().into(),
+                            v => v.to_string().as_str().into(),
+                        })
+                        .collect();
+                    w.write_batch(&vals, Some(&def_levels), None)?;
+                },
+                _ => unreachable!("parquet column writer doesn't match schema"),
+            }
+            column.close()?;
+        }
+        row_group.close()?;
+    }
+    writer.close().context("failed to finish parquet file")?;
+    Ok(())
+}
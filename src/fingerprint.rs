@@ -0,0 +1,504 @@
+//! Fingerprinting of map audio, charts and covers, for finding re-uploads and maps copied from
+//! other mappers
+//!
+//! Audio and charts are each reduced to a set of short overlapping snippets ('shingles') which are
+//! summarised by a MinHash signature - the fraction of signature entries two maps share estimates
+//! how much their sets of snippets overlap. Covers get a 64 bit difference hash, which changes by a
+//! few bits at most when an image is resized or recompressed. Candidate pairs are found by
+//! bucketing bands of the signatures, so not every pair of songs needs comparing.
+use anyhow::{Context, Result, anyhow};
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Read};
+
+use super::audio;
+use super::dats::{self, InfoDat};
+use super::db::{self, DbError, DbResult, SqliteConnection, retry_busy};
+use super::hash::{fnv1a, mix};
+use super::models::{BeatSaverMap, DuplicatePair};
+use super::num_to_key;
+
+/// Bump this whenever fingerprints change, so they get recomputed
+pub const FINGERPRINT_VERSION: i64 = 1;
+
+// Entries in a MinHash signature, and how they're split into bands for finding candidates - two
+// maps become candidates if all entries in any band match, which is likely above about
+// (1/BANDS)^(1/ROWS) = 50% similarity
+const SIGNATURE_LEN: usize = 64;
+const SIGNATURE_BANDS: usize = 16;
+const SIGNATURE_ROWS: usize = SIGNATURE_LEN / SIGNATURE_BANDS;
+// Buckets bigger than this are almost certainly degenerate (like charts with one repeated note), and
+// would make for a huge number of candidates
+const MAX_BUCKET_SIZE: usize = 1000;
+
+// Audio is split into frames, and each frame is marked as louder, quieter or about the same as the
+// one before. Shingles are runs of these marks.
+const AUDIO_FRAMES_PER_SEC: u32 = 5;
+const AUDIO_FRAME_PHASES: usize = 16;
+const AUDIO_CHANGE_RATIO: f32 = 1.25;
+const AUDIO_SHINGLE_FRAMES: usize = 12;
+const AUDIO_QUIETER: u64 = 0;
+const AUDIO_STEADY: u64 = 1;
+const AUDIO_LOUDER: u64 = 2;
+
+// Charts are a sequence of notes, each with the time since the previous note (in fractions of a
+// beat, so offsetting the whole chart doesn't matter), position, color and direction. Shingles are
+// runs of these notes.
+const CHART_TIME_STEPS_PER_BEAT: f64 = 16.0;
+const CHART_SHINGLE_NOTES: usize = 4;
+
+// Covers are shrunk to this size in grayscale, and each bit of the hash is whether a pixel is darker
+// than the one to its right
+const COVER_HASH_WIDTH: u32 = 9;
+const COVER_HASH_HEIGHT: u32 = 8;
+
+/// Charts at least this similar are duplicates whatever else differs
+pub const CHART_DUPLICATE_SIMILARITY: f64 = 0.8;
+/// Charts at least this similar are duplicates if they're for the same audio
+pub const CHART_EDITED_SIMILARITY: f64 = 0.5;
+/// Audio at least this similar is the same song
+pub const AUDIO_SAME_SIMILARITY: f64 = 0.5;
+/// Covers differing by at most this many bits are the same image
+pub const COVER_SAME_DISTANCE: i64 = 6;
+
+/// Everything we fingerprint about a map. Any part may be missing if it couldn't be worked out -
+/// `errors` says why.
+#[derive(Debug, Clone, Default)]
+#[derive(Serialize, Deserialize)]
+pub struct Fingerprint {
+    /// A hash of the raw audio file
+    pub audio_hash: Option<u64>,
+    /// MinHash signature of the audio
+    pub audio: Option<Vec<u64>>,
+    /// MinHash signature of the charts of every beatmap together
+    pub chart: Option<Vec<u64>>,
+    /// Difference hash of the cover image
+    pub cover: Option<u64>,
+    pub errors: Vec<String>,
+}
+
+/// Fingerprint a map zip. This only fails if the zip or its info.dat can't be read at all -
+/// problems with individual parts are recorded in the fingerprint.
+pub fn fingerprint_zip(zipdata: &[u8]) -> Result<Fingerprint> {
+    let mut zip = zip::ZipArchive::new(io::Cursor::new(zipdata)).context("failed to load zip")?;
+    let infodat: InfoDat = {
+        let name = (0..zip.len())
+            .filter_map(|i| zip.by_index(i).ok().map(|entry| entry.name().to_owned()))
+            .find(|name| name.eq_ignore_ascii_case("info.dat"))
+            .ok_or_else(|| anyhow!("no info.dat found in zip"))?;
+        let data = read_zip_entry(&mut zip, &name)?;
+        serde_json::from_slice(&data).context("failed to parse info.dat")?
+    };
+
+    let mut fingerprint = Fingerprint::default();
+    macro_rules! record_error {
+        ($what:expr, $e:expr) => {
+            match $e {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    fingerprint.errors.push(format!("{}: {:#}", $what, e));
+                    None
+                },
+            }
+        };
+    }
+
+    if let Some(song) = record_error!("audio", read_zip_entry(&mut zip, &infodat.song_filename)) {
+        fingerprint.audio_hash = Some(fnv1a(&song));
+        fingerprint.audio = record_error!("audio", audio_shingles(&song)).and_then(|shingles| minhash(&shingles));
+    }
+
+    let mut chart_shingles = HashSet::new();
+    let beatmap_filenames: Vec<_> = infodat.difficulty_beatmap_sets.iter()
+        .flat_map(|set| set.difficulty_beatmaps.iter().map(|beatmap| beatmap.beatmap_filename.clone()))
+        .collect();
+    for filename in beatmap_filenames {
+        let what = format!("chart {}", filename);
+        let beatmap = record_error!(what, read_zip_entry(&mut zip, &filename).and_then(|dat| dats::parse_beatmap(&dat)));
+        if let Some(beatmap) = beatmap {
+            chart_shingles.extend(chart_shingles_of(&beatmap))
+        }
+    }
+    fingerprint.chart = minhash(&chart_shingles);
+
+    if let Some(cover_filename) = infodat.cover_image_filename.as_ref() {
+        let cover = read_zip_entry(&mut zip, cover_filename).and_then(|cover| cover_hash(&cover));
+        fingerprint.cover = record_error!("cover", cover);
+    }
+
+    Ok(fingerprint)
+}
+
+fn read_zip_entry(zip: &mut zip::ZipArchive<impl Read + io::Seek>, name: &str) -> Result<Vec<u8>> {
+    let mut entry = zip.by_name(name).with_context(|| format!("failed to find {} in zip", name))?;
+    let mut data = vec![];
+    entry.read_to_end(&mut data).with_context(|| format!("failed to read {} from zip", name))?;
+    Ok(data)
+}
+
+fn audio_shingles(song: &[u8]) -> Result<HashSet<u64>> {
+    let (sample_rate, samples) = audio::decode_mono(song)?;
+    // Frames overlap, starting every 1/AUDIO_FRAME_PHASES of a frame, so that a copy with a little
+    // silence added to the start will have its frames line up with some phase of the original's.
+    // Starts are worked out from the time rather than stepping by a whole number of samples, which
+    // would drift differently at different sample rates.
+    let frame_len = (sample_rate / AUDIO_FRAMES_PER_SEC).max(1) as usize;
+    let hops_per_sec = f64::from(AUDIO_FRAMES_PER_SEC) * AUDIO_FRAME_PHASES as f64;
+    let energies: Vec<f32> = (0..)
+        .map(|hop| (hop as f64 * f64::from(sample_rate) / hops_per_sec).round() as usize)
+        .take_while(|&start| start + frame_len <= samples.len())
+        .map(|start| {
+            let frame = &samples[start..start + frame_len];
+            frame.iter().map(|s| s * s).sum::<f32>() / frame_len as f32
+        })
+        .collect();
+    // How each frame compares to the one a whole frame before it
+    let changes: Vec<u64> = energies.iter().zip(energies.iter().skip(AUDIO_FRAME_PHASES))
+        .map(|(&prev, &energy)| {
+            if energy > prev * AUDIO_CHANGE_RATIO {
+                AUDIO_LOUDER
+            } else if energy * AUDIO_CHANGE_RATIO < prev {
+                AUDIO_QUIETER
+            } else {
+                AUDIO_STEADY
+            }
+        })
+        .collect();
+    let span = (AUDIO_SHINGLE_FRAMES - 1) * AUDIO_FRAME_PHASES + 1;
+    Ok((0..changes.len().saturating_sub(span - 1))
+        .map(|start| changes[start..start + span].iter().step_by(AUDIO_FRAME_PHASES))
+        // Silence or a constant tone says nothing about the song
+        .filter(|frames| frames.clone().any(|&c| c != AUDIO_STEADY))
+        .map(|frames| frames.fold(0, |acc, &c| acc << 2 | c))
+        .collect())
+}
+
+fn chart_shingles_of(beatmap: &dats::Beatmap) -> Vec<u64> {
+    let mut notes: Vec<&dats::Note> = beatmap.notes.iter().collect();
+    // Notes at the same time can be in any order in the file
+    notes.sort_by(|a, b| {
+        a.time.partial_cmp(&b.time).unwrap_or(cmp::Ordering::Equal)
+            .then((a.color, a.line_index, a.line_layer).cmp(&(b.color, b.line_index, b.line_layer)))
+    });
+    let mut prev_time: Option<f64> = None;
+    let tokens: Vec<[i64; 5]> = notes.into_iter()
+        .map(|note| {
+            let steps = prev_time.map_or(0, |prev| ((note.time - prev) * CHART_TIME_STEPS_PER_BEAT).round() as i64);
+            prev_time = Some(note.time);
+            [steps, note.line_index, note.line_layer, note.color, note.cut_direction]
+        })
+        .collect();
+    tokens.windows(CHART_SHINGLE_NOTES)
+        .map(|window| {
+            let bytes: Vec<u8> = window.iter().flatten().flat_map(|n| n.to_le_bytes().to_vec()).collect();
+            fnv1a(&bytes)
+        })
+        .collect()
+}
+
+fn cover_hash(cover: &[u8]) -> Result<u64> {
+    let image = image::load_from_memory(cover).context("failed to decode cover")?
+        .resize_exact(COVER_HASH_WIDTH, COVER_HASH_HEIGHT, image::imageops::FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0;
+    for y in 0..COVER_HASH_HEIGHT {
+        for x in 0..COVER_HASH_WIDTH - 1 {
+            let darker = image.get_pixel(x, y).0[0] < image.get_pixel(x + 1, y).0[0];
+            hash = hash << 1 | darker as u64
+        }
+    }
+    Ok(hash)
+}
+
+fn minhash<'a>(shingles: impl IntoIterator<Item=&'a u64> + Clone) -> Option<Vec<u64>> {
+    let signature: Vec<u64> = (0..SIGNATURE_LEN as u64)
+        .map(|i| {
+            let seed = mix(i + 1);
+            shingles.clone().into_iter().map(|&s| mix(s ^ seed)).min()
+        })
+        .collect::<Option<_>>()?;
+    Some(signature)
+}
+
+fn signature_similarity(a: &[u64], b: &[u64]) -> f64 {
+    let same = a.iter().zip(b).filter(|(a, b)| a == b).count();
+    same as f64 / SIGNATURE_LEN as f64
+}
+
+/// Compare two fingerprinted songs, giving whatever scores both have the fingerprints for
+pub fn compare(key_a: i64, a: &Fingerprint, key_b: i64, b: &Fingerprint) -> DuplicatePair {
+    let (key_a, key_b) = (key_a.min(key_b), key_a.max(key_b));
+    DuplicatePair {
+        key_a,
+        key_b,
+        audio_exact: a.audio_hash.is_some() && a.audio_hash == b.audio_hash,
+        audio: a.audio.as_ref().zip(b.audio.as_ref()).map(|(a, b)| signature_similarity(a, b)),
+        chart: a.chart.as_ref().zip(b.chart.as_ref()).map(|(a, b)| signature_similarity(a, b)),
+        cover: a.cover.zip(b.cover).map(|(a, b)| (a ^ b).count_ones().into()),
+    }
+}
+
+/// Whether the scores of a pair make them likely duplicates. Lots of maps share audio (popular
+/// songs get mapped many times), so that alone isn't enough.
+pub fn is_duplicate(pair: &DuplicatePair) -> bool {
+    let same_audio = pair.audio_exact || pair.audio.unwrap_or(0.0) >= AUDIO_SAME_SIMILARITY;
+    let chart = pair.chart.unwrap_or(0.0);
+    let same_cover = matches!(pair.cover, Some(d) if d <= COVER_SAME_DISTANCE);
+    chart >= CHART_DUPLICATE_SIMILARITY ||
+        (same_audio && chart >= CHART_EDITED_SIMILARITY) ||
+        (same_audio && same_cover)
+}
+
+/// Find every pair of songs that are likely duplicates
+pub fn find_duplicates(songs: &[(i64, Fingerprint)]) -> Vec<DuplicatePair> {
+    let mut buckets: HashMap<(&str, u64), Vec<usize>> = HashMap::new();
+    for (i, (_, fingerprint)) in songs.iter().enumerate() {
+        if let Some(audio_hash) = fingerprint.audio_hash {
+            buckets.entry(("audio_hash", audio_hash)).or_default().push(i)
+        }
+        for &(kind, signature) in [("audio", &fingerprint.audio), ("chart", &fingerprint.chart)].iter() {
+            let signature = match signature {
+                Some(signature) => signature,
+                None => continue,
+            };
+            for (band, rows) in signature.chunks(SIGNATURE_ROWS).enumerate() {
+                let band_hash = rows.iter().fold(mix(band as u64), |acc, &r| mix(acc ^ r));
+                buckets.entry((kind, band_hash)).or_default().push(i)
+            }
+        }
+    }
+
+    let mut candidates = HashSet::new();
+    for ((kind, _), bucket) in buckets {
+        if bucket.len() > MAX_BUCKET_SIZE {
+            warn!("Skipping {} bucket of {} songs", kind, bucket.len());
+            continue
+        }
+        for (n, &i) in bucket.iter().enumerate() {
+            for &j in &bucket[n + 1..] {
+                candidates.insert((i.min(j), i.max(j)));
+            }
+        }
+    }
+    info!("Comparing {} candidate pairs", candidates.len());
+
+    let mut pairs: Vec<DuplicatePair> = candidates.into_iter()
+        .map(|(i, j)| compare(songs[i].0, &songs[i].1, songs[j].0, &songs[j].1))
+        .filter(is_duplicate)
+        .collect();
+    pairs.sort_by_key(|pair| (pair.key_a, pair.key_b));
+    pairs
+}
+
+/// Group pairs into clusters of songs that are all (transitively) duplicates of each other, biggest
+/// first. Each cluster's keys are in order, so the original upload is usually first.
+pub fn clusters(pairs: &[DuplicatePair]) -> Vec<Vec<i64>> {
+    // Union-find, with each key pointing towards the root of its cluster
+    fn root(parents: &mut HashMap<i64, i64>, key: i64) -> i64 {
+        let parent = *parents.entry(key).or_insert(key);
+        if parent == key {
+            return key
+        }
+        let root = root(parents, parent);
+        parents.insert(key, root);
+        root
+    }
+    let mut parents = HashMap::new();
+    for pair in pairs {
+        let (a, b) = (root(&mut parents, pair.key_a), root(&mut parents, pair.key_b));
+        parents.insert(a.max(b), a.min(b));
+    }
+
+    let keys: Vec<i64> = parents.keys().copied().collect();
+    let mut clusters: BTreeMap<i64, Vec<i64>> = BTreeMap::new();
+    for key in keys {
+        let root = root(&mut parents, key);
+        clusters.entry(root).or_default().push(key)
+    }
+    let mut clusters: Vec<Vec<i64>> = clusters.into_values().map(|mut keys| { keys.sort(); keys }).collect();
+    clusters.sort_by_key(|keys| (cmp::Reverse(keys.len()), keys[0]));
+    clusters
+}
+
+/// Fingerprint every song that doesn't have an up to date fingerprint
+pub fn update_fingerprints(conn: &SqliteConnection) -> Result<()> {
+    let hashes = retry_busy(|| db::songs_to_fingerprint(conn, FINGERPRINT_VERSION)).context("failed to find songs to fingerprint")?;
+    let num_hashes = hashes.len();
+    info!("Fingerprinting {} songs", num_hashes);
+    for (i, hash) in hashes.into_iter().enumerate() {
+        info!("Fingerprinting {} ({}/{})", hash, i+1, num_hashes);
+        let zip = match retry_busy(|| db::get_song_zipdata(conn, &hash)) {
+            Ok(zip) => zip,
+            // It's possible for song data to vanish while this is running
+            Err(DbError::NotFound) => {
+                warn!("Song data for {} went missing, skipping", hash);
+                continue
+            },
+            Err(e) => return Err(e).with_context(|| format!("failed to load zipdata for {}", hash)),
+        };
+        // A zip that can't be read still gets an (empty) fingerprint, so it isn't retried every run
+        let fingerprint = fingerprint_zip(&zip).unwrap_or_else(|e| {
+            Fingerprint { errors: vec![format!("{:#}", e)], ..Fingerprint::default() }
+        });
+        for error in fingerprint.errors.iter() {
+            warn!("Fingerprinting {}: {}", hash, error)
+        }
+        let fingerprint = serde_json::to_vec(&fingerprint).expect("failed to serialize fingerprint");
+        retry_busy(|| db::set_song_fingerprint(conn, &hash, FINGERPRINT_VERSION, fingerprint.clone()))
+            .with_context(|| format!("failed to save fingerprint for {}", hash))?
+    }
+    Ok(())
+}
+
+/// Find duplicates among the fingerprinted songs, replacing the pairs from the last run. Returns
+/// how many pairs were found.
+pub fn update_duplicates(conn: &SqliteConnection) -> Result<usize> {
+    let fingerprints = retry_busy(|| db::live_song_fingerprints(conn, FINGERPRINT_VERSION)).context("failed to load fingerprints")?;
+    let songs = fingerprints.into_iter()
+        .map(|(key, fingerprint)| {
+            let fingerprint = serde_json::from_slice(&fingerprint)
+                .with_context(|| format!("failed to deserialize fingerprint for {}", num_to_key(key)))?;
+            Ok((key, fingerprint))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    info!("Finding duplicates among {} songs", songs.len());
+    let pairs = find_duplicates(&songs);
+    let num_pairs = pairs.len();
+    retry_busy(|| db::replace_duplicate_pairs(conn, pairs.clone())).context("failed to save duplicate pairs")?;
+    Ok(num_pairs)
+}
+
+/// Clusters of likely duplicates, as found by the last [`update_duplicates`]
+#[derive(Serialize)]
+pub struct DuplicateReport {
+    pub clusters: Vec<DuplicateCluster>,
+}
+
+#[derive(Serialize)]
+pub struct DuplicateCluster {
+    pub songs: Vec<DuplicateSong>,
+    pub pairs: Vec<DuplicateReportPair>,
+}
+
+#[derive(Serialize)]
+pub struct DuplicateSong {
+    pub key: String,
+    pub hash: Option<String>,
+    pub name: Option<String>,
+    pub uploader: Option<String>,
+    pub uploaded: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// A [`DuplicatePair`] with BeatSaver keys
+#[derive(Serialize)]
+pub struct DuplicateReportPair {
+    pub key_a: String,
+    pub key_b: String,
+    pub audio_exact: bool,
+    pub audio: Option<f64>,
+    pub chart: Option<f64>,
+    pub cover: Option<i64>,
+}
+
+/// Build a report of the duplicate clusters - just the one containing `key`, if given
+pub fn duplicate_report(conn: &SqliteConnection, key: Option<i64>) -> DbResult<DuplicateReport> {
+    let pairs = db::get_duplicate_pairs(conn, None)?;
+    let mut report = DuplicateReport { clusters: vec![] };
+    for keys in clusters(&pairs) {
+        if matches!(key, Some(key) if !keys.contains(&key)) {
+            continue
+        }
+        let mut songs = vec![];
+        for &song_key in keys.iter() {
+            let meta = db::get_song_meta(conn, song_key)?;
+            let bsmeta: Option<BeatSaverMap> = meta.as_ref()
+                .map(|meta| serde_json::from_slice(&meta.bsmeta))
+                .transpose()
+                .map_err(|e| DbError::Corrupt(format!("failed to deserialize bsmeta for {}: {}", num_to_key(song_key), e)))?;
+            songs.push(DuplicateSong {
+                key: num_to_key(song_key),
+                hash: meta.map(|meta| meta.hash),
+                name: bsmeta.as_ref().map(|bsmeta| format!("{} {}", bsmeta.metadata.song_name, bsmeta.metadata.song_sub_name)),
+                uploader: bsmeta.as_ref().map(|bsmeta| bsmeta.uploader.name.clone()),
+                uploaded: bsmeta.map(|bsmeta| bsmeta.uploaded),
+            })
+        }
+        let pairs = pairs.iter()
+            .filter(|pair| keys.binary_search(&pair.key_a).is_ok())
+            .map(|pair| DuplicateReportPair {
+                key_a: num_to_key(pair.key_a),
+                key_b: num_to_key(pair.key_b),
+                audio_exact: pair.audio_exact,
+                audio: pair.audio,
+                chart: pair.chart,
+                cover: pair.cover,
+            })
+            .collect();
+        report.clusters.push(DuplicateCluster { songs, pairs })
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key_a: i64, key_b: i64) -> DuplicatePair {
+        DuplicatePair { key_a, key_b, audio_exact: false, audio: None, chart: None, cover: None }
+    }
+
+    #[test]
+    fn duplicate_scores() {
+        let same_chart = DuplicatePair { chart: Some(0.85), ..pair(1, 2) };
+        assert!(is_duplicate(&same_chart));
+        // Popular songs get mapped many times, so the same audio needs something else alike too
+        let same_audio = DuplicatePair { audio: Some(0.9), ..pair(1, 2) };
+        assert!(!is_duplicate(&same_audio));
+        assert!(is_duplicate(&DuplicatePair { chart: Some(0.6), ..same_audio.clone() }));
+        assert!(!is_duplicate(&DuplicatePair { chart: Some(0.6), audio: Some(0.4), ..pair(1, 2) }));
+        let exact_audio = DuplicatePair { audio_exact: true, ..pair(1, 2) };
+        assert!(is_duplicate(&DuplicatePair { cover: Some(6), ..exact_audio.clone() }));
+        assert!(!is_duplicate(&DuplicatePair { cover: Some(7), ..exact_audio }));
+    }
+
+    #[test]
+    fn compare_fingerprints() {
+        let shingles: Vec<u64> = (0..100).collect();
+        let a = Fingerprint { audio_hash: Some(1), chart: minhash(&shingles), cover: Some(0b1011), ..Default::default() };
+        let b = Fingerprint { audio_hash: Some(1), chart: minhash(&shingles), cover: Some(0b0001), ..Default::default() };
+        assert_eq!(compare(9, &a, 4, &b), DuplicatePair {
+            key_a: 4,
+            key_b: 9,
+            audio_exact: true,
+            audio: None,
+            chart: Some(1.0),
+            cover: Some(2),
+        });
+        // No audio isn't the same audio
+        let none = Fingerprint::default();
+        assert!(!compare(1, &none, 2, &none).audio_exact);
+    }
+
+    #[test]
+    fn find_similar_charts() {
+        let chart = |shingles: std::ops::Range<u64>| Fingerprint {
+            chart: minhash(&shingles.collect::<Vec<_>>()),
+            ..Default::default()
+        };
+        let songs = vec![(10, chart(0..100)), (7, chart(1000..1100)), (5, chart(0..100))];
+        assert_eq!(find_duplicates(&songs), vec![DuplicatePair { chart: Some(1.0), ..pair(5, 10) }]);
+    }
+
+    #[test]
+    fn duplicate_clusters() {
+        assert_eq!(clusters(&[]), Vec::<Vec<i64>>::new());
+        let pairs = [pair(8, 9), pair(3, 7), pair(5, 6), pair(1, 3)];
+        // Biggest first, then by first key
+        assert_eq!(clusters(&pairs), vec![vec![1, 3, 7], vec![5, 6], vec![8, 9]]);
+        // Joining two clusters through a later pair
+        let pairs = [pair(1, 2), pair(3, 4), pair(2, 4)];
+        assert_eq!(clusters(&pairs), vec![vec![1, 2, 3, 4]]);
+    }
+}
@@ -0,0 +1,14 @@
+//! Hashes whose output is stored or shown to plugins, so can never change (unlike the std hashers)
+
+/// FNV-1a
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// The splitmix64 finaliser, for making a family of hash functions from one hash or a stream of
+/// random numbers from a counter
+pub fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
@@ -0,0 +1,38 @@
+#![recursion_limit="256"]
+
+//! The core of bsmeta - models, map processing, the analysis plugin runtime and database access -
+//! for reuse by the bsmeta CLI and server, and by other tools.
+
+pub mod aggregate;
+pub mod audio;
+pub mod beatsaver;
+pub mod dats;
+pub mod db;
+pub mod export;
+pub mod fingerprint;
+pub mod hash;
+pub mod migrations;
+pub mod models;
+pub mod plugins;
+pub mod search;
+pub mod wasm;
+
+/// Convert a BeatSaver key (hex) to the integer we store it as. Panics if it isn't a key - use
+/// [`parse_key`] for keys from users or plugins.
+pub fn key_to_num<T: AsRef<str>>(k: &T) -> i64 {
+    parse_key(k.as_ref()).expect("can't parse key")
+}
+
+/// Convert a BeatSaver key (1 to 8 hex digits) to the integer we store it as, if it is one
+pub fn parse_key(k: &str) -> Option<i64> {
+    if k.is_empty() || k.len() > 8 || !k.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None
+    }
+    u32::from_str_radix(k, 16).ok().map(i64::from)
+}
+
+/// Convert a stored integer key back into a BeatSaver key
+pub fn num_to_key(n: i64) -> String {
+    assert!(n >= 0);
+    format!("{:x}", n) // format as hex
+}
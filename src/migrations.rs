@@ -0,0 +1,111 @@
+use async_std::task;
+use chrono::Utc;
+use log::info;
+use sqlx::prelude::*;
+
+use super::db::{self, DbResult, SqliteConnection};
+
+struct Migration {
+    version: i64,
+    name: &'static str,
+    sql: &'static str,
+}
+
+// Append only! Once a migration has been applied to a database it must never be edited - write a
+// new one instead
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial", sql: include_str!("../migrations/0001_initial.sql") },
+    Migration { version: 2, name: "search_sync", sql: include_str!("../migrations/0002_search_sync.sql") },
+    Migration { version: 3, name: "search_backends", sql: include_str!("../migrations/0003_search_backends.sql") },
+    Migration { version: 4, name: "beatmap_analysis", sql: include_str!("../migrations/0004_beatmap_analysis.sql") },
+    Migration { version: 5, name: "aggregate_analysis", sql: include_str!("../migrations/0005_aggregate_analysis.sql") },
+    Migration { version: 6, name: "fingerprints", sql: include_str!("../migrations/0006_fingerprints.sql") },
+    Migration { version: 7, name: "analysis_logs", sql: include_str!("../migrations/0007_analysis_logs.sql") },
+    Migration { version: 8, name: "analysis_versions", sql: include_str!("../migrations/0008_analysis_versions.sql") },
+];
+
+const SCHEMA_VERSION_TABLE_SQL: &str = include_str!("../migrations/schema_version.sql");
+
+/// The schema version this binary expects the database to be at
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// The schema version the database is currently at, 0 if nothing has been applied
+pub fn current_version(conn: &SqliteConnection) -> DbResult<i64> {
+    task::block_on(async {
+        let (has_version_table,): (bool,) = sqlx::query_as("SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'")
+            .fetch_one(conn).await?;
+        if !has_version_table {
+            return Ok(0)
+        }
+        let (version,): (Option<i64>,) = sqlx::query_as("SELECT max(version) FROM schema_version")
+            .fetch_one(conn).await?;
+        Ok::<_, db::DbError>(version.unwrap_or(0))
+    })
+}
+
+// Databases created before migrations existed have the initial schema but no record of it, so
+// mark them as being at the initial version. Returns the version the database is (or would be) at.
+fn baseline_legacy(conn: &SqliteConnection, dry_run: bool) -> i64 {
+    let current = current_version(conn).expect("failed to get schema version");
+    let (has_songs_table,): (bool,) = task::block_on(
+        sqlx::query_as("SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'tSong'").fetch_one(conn)
+    ).expect("failed to check for tSong table");
+    if !has_songs_table || current != 0 {
+        return current
+    }
+    let initial = &MIGRATIONS[0];
+    println!("Database predates migrations, marking migration {} ({}) as applied", initial.version, initial.name);
+    if !dry_run {
+        task::block_on(async {
+            conn.execute(SCHEMA_VERSION_TABLE_SQL).await.expect("failed to create schema_version table");
+            record_version(conn, initial).await.expect("failed to record baseline version");
+        })
+    }
+    initial.version
+}
+
+async fn record_version<'c, E: sqlx::Executor<'c, Database=sqlx::Sqlite>>(conn: E, migration: &Migration) -> Result<(), sqlx::Error> {
+    let res = sqlx::query("INSERT INTO schema_version (version, name, applied_at) VALUES (?, ?, ?)")
+        .bind(migration.version)
+        .bind(migration.name)
+        .bind(Utc::now().timestamp_millis())
+        .execute(conn).await?;
+    assert_eq!(res.rows_affected(), 1, "record version {}", migration.version);
+    Ok(())
+}
+
+/// Apply any migrations the database doesn't have yet, each in its own transaction. With
+/// `dry_run`, just print what would be done.
+pub fn migrate(dry_run: bool) {
+    let conn = &db::connect().expect("failed to connect to database");
+
+    let current = baseline_legacy(conn, dry_run);
+    let latest = latest_version();
+    if current > latest {
+        panic!("database is at schema version {}, which is newer than this binary knows about ({})", current, latest)
+    }
+    let pending: Vec<_> = MIGRATIONS.iter().filter(|m| m.version > current).collect();
+    println!("Database is at schema version {}, {} migrations to apply", current, pending.len());
+
+    for migration in pending {
+        if dry_run {
+            println!("Would apply migration {} ({}):\n{}", migration.version, migration.name, migration.sql);
+            continue
+        }
+        println!("Applying migration {} ({})", migration.version, migration.name);
+        task::block_on(async {
+            conn.execute(SCHEMA_VERSION_TABLE_SQL).await?;
+            let mut tx = conn.begin().await?;
+            (&mut tx).execute(migration.sql).await?;
+            record_version(&mut tx, migration).await?;
+            tx.commit().await
+        }).unwrap_or_else(|e| panic!("failed to apply migration {} ({}): {}", migration.version, migration.name, e));
+        info!("Applied migration {}", migration.version);
+    }
+
+    if !dry_run {
+        println!("Database is now at schema version {}", current_version(conn).expect("failed to get schema version"));
+    }
+}
@@ -0,0 +1,162 @@
+//! Types for the data we store about songs
+use chrono::Utc;
+use decorum::R32;
+use serde::{Deserialize, Serialize};
+
+use super::audio::AudioCodec;
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Song {
+    // TODO: make this u32
+    pub key: i64,
+    pub deleted: bool,
+    pub tstamp: i64,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct SongMeta {
+    // TODO: make this u32
+    pub key: i64,
+    pub hash: String,
+    pub bsmeta: Vec<u8>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct SongData {
+    // TODO: make this u32
+    pub hash: String,
+    pub zipdata: Vec<u8>,
+    pub data: Vec<u8>,
+    pub extra_meta: ExtraMeta,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct ExtraMeta {
+    pub song_duration: Option<R32>,
+    pub song_size: Option<u32>,
+    pub zip_size: u32,
+    // These were added after the fields above, so may be missing for older songs
+    #[serde(default)]
+    pub song_codec: Option<AudioCodec>,
+    #[serde(default)]
+    pub song_sample_rate: Option<u32>,
+    #[serde(default)]
+    pub song_channels: Option<u8>,
+    /// Why the song couldn't be probed, if it couldn't be
+    #[serde(default)]
+    pub song_error: Option<String>,
+}
+
+impl<DB: sqlx::Database> sqlx::Type<DB> for ExtraMeta
+    where
+        Vec<u8>: sqlx::Type<DB>
+{
+    fn type_info() -> <DB as sqlx::Database>::TypeInfo {
+        <Vec<u8> as sqlx::Type<DB>>::type_info()
+    }
+    fn compatible(ty: &<DB as sqlx::Database>::TypeInfo) -> bool {
+        <Vec<u8> as sqlx::Type<DB>>::compatible(ty)
+    }
+}
+
+impl<'q, DB: sqlx::Database> sqlx::Encode<'q, DB> for ExtraMeta
+where
+    Vec<u8>: sqlx::Encode<'q, DB>
+{
+    fn encode_by_ref(&self, buf: &mut <DB as sqlx::database::HasArguments<'q>>::ArgumentBuffer) -> sqlx::encode::IsNull {
+        let bytes = serde_json::to_vec(&self).expect("failed to serialize extrameta");
+        <Vec<u8> as sqlx::Encode<'q, DB>>::encode_by_ref(&bytes, buf)
+    }
+    fn encode(self, buf: &mut <DB as sqlx::database::HasArguments<'q>>::ArgumentBuffer) -> sqlx::encode::IsNull {
+        let bytes = serde_json::to_vec(&self).expect("failed to serialize extrameta");
+        <Vec<u8> as sqlx::Encode<'q, DB>>::encode(bytes, buf)
+    }
+}
+
+/// Two songs that look like duplicates of each other, with how alike each of their fingerprints
+/// are - see [`crate::fingerprint`]
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize)]
+pub struct DuplicatePair {
+    pub key_a: i64,
+    pub key_b: i64,
+    /// The audio files are byte for byte identical
+    pub audio_exact: bool,
+    /// Estimated similarity of the audio, 0 to 1
+    pub audio: Option<f64>,
+    /// Estimated similarity of the charts, 0 to 1
+    pub chart: Option<f64>,
+    /// Number of bits that differ between the cover hashes, 0 to 64
+    pub cover: Option<i64>,
+}
+
+/// The subset of the BeatSaver map JSON that we look at - the full JSON is kept in [`SongMeta`]
+#[derive(Deserialize)]
+pub struct BeatSaverMap {
+    #[serde(rename = "id")]
+    pub key: String,
+    pub description: String,
+    pub metadata: BeatSaverMapMetadata,
+    pub stats: BeatSaverMapStats,
+    pub uploaded: chrono::DateTime<Utc>,
+    pub uploader: BeatSaverMapUploader,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: chrono::DateTime<Utc>,
+    pub versions: Vec<BeatSaverMapVersion>,
+}
+impl BeatSaverMap {
+    pub fn check(&self) {
+        self.current_version(); // make sure assertions in here pass
+        // TODO: relax this at some point?
+        assert_eq!(self.versions.len(), 1);
+    }
+
+    pub fn current_version(&self) -> &BeatSaverMapVersion {
+        assert_eq!(
+            self.versions.iter()
+                .filter(|v| v.state == BeatSaverMapVersionState::Published)
+                .count(),
+            1
+        );
+        self.versions.iter()
+            .filter(|v| v.state == BeatSaverMapVersionState::Published)
+            .next()
+            .unwrap()
+    }
+}
+#[derive(Deserialize)]
+pub struct BeatSaverMapMetadata {
+    #[serde(rename = "songName")]
+    pub song_name: String,
+    #[serde(rename = "songSubName")]
+    pub song_sub_name: String,
+}
+#[derive(Deserialize)]
+pub struct BeatSaverMapStats {
+    pub upvotes: u32,
+    pub downvotes: u32,
+}
+#[derive(Deserialize)]
+pub struct BeatSaverMapUploader {
+    pub name: String,
+}
+#[derive(Deserialize)]
+pub struct BeatSaverMapVersion {
+    pub hash: String,
+    pub diffs: Vec<BeatSaverMapDifficulty>,
+    pub state: BeatSaverMapVersionState,
+}
+#[derive(Deserialize)]
+pub struct BeatSaverMapDifficulty {
+    pub characteristic: String,
+    pub difficulty: String,
+}
+#[derive(Deserialize)]
+#[derive(PartialEq, Eq)]
+pub enum BeatSaverMapVersionState {
+    Feedback,
+    Published,
+    Testplay,
+    Uploaded,
+}
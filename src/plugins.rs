@@ -0,0 +1,305 @@
+//! Packaging plugins and interps from plugins/pluginlist.json and plugins/interplist.json, and
+//! plugins registered from scripts while running, which don't need a rebuild to be picked up
+use anyhow::{Context, Result, anyhow, bail};
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::db::{self, SqliteConnection, retry_busy};
+use super::wasm::{self, AnalysisPlugin, PluginInfo, PluginLevel, PluginSchema};
+
+/// Where plugin sources and the list of them live
+pub const PLUGIN_SRC_DIR: &str = "plugins";
+/// Where built plugins and interps go
+pub const PLUGIN_DIST_DIR: &str = "plugins/dist";
+/// Where registered plugins are kept, as `<name>/<version>.tar` and `<name>/<version>.json`
+pub const REGISTERED_PLUGINS_DIR: &str = "plugins/registered";
+/// The interps that scripts can be run with, as written by `build-plugins`
+pub const INTERP_LIST_PATH: &str = "plugins/dist/interplist.json";
+/// Where the files every script for an interp gets (like the Python stdlib) are, as `<interp>.tar`
+pub const INTERP_BASE_DIR: &str = "plugins/dist/interps";
+
+/// The most the extra files sent along with a script can add up to
+pub const MAX_SCRIPT_FILES_BYTES: usize = 16 * 1024 * 1024;
+
+/// An entry in the interp list
+#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct InterpInfo {
+    /// Where in /work the interp runs its script from
+    pub script: String,
+}
+
+// An entry in plugins/pluginlist.json
+#[derive(Deserialize)]
+struct PluginSource {
+    #[serde(flatten)]
+    info: PluginInfo,
+    /// From a path under plugins/ to where it goes in the plugin
+    files: BTreeMap<String, String>,
+}
+
+// An entry in plugins/interplist.json
+#[derive(Deserialize)]
+struct InterpSource {
+    #[serde(flatten)]
+    info: InterpInfo,
+    files: BTreeMap<String, String>,
+}
+
+fn append_file(builder: &mut tar::Builder<Vec<u8>>, path: &str, data: &[u8]) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, path, data)
+}
+
+fn read_source_list<T: serde::de::DeserializeOwned>(name: &str) -> Result<BTreeMap<String, T>> {
+    let list_path = Path::new(PLUGIN_SRC_DIR).join(name);
+    let list = fs::read(&list_path).with_context(|| format!("failed to read {}", list_path.display()))?;
+    serde_json::from_slice(&list).with_context(|| format!("failed to parse {}", list_path.display()))
+}
+
+// Tar up files under plugins/, from where they are to where they go
+fn build_tar(files: &BTreeMap<String, String>, tar_path: &Path) -> Result<()> {
+    let mut builder = tar::Builder::new(vec![]);
+    for (from, to) in files.iter() {
+        let data = fs::read(Path::new(PLUGIN_SRC_DIR).join(from)).with_context(|| format!("failed to read {}", from))?;
+        append_file(&mut builder, to, &data)?;
+    }
+    fs::write(tar_path, builder.into_inner()?).with_context(|| format!("failed to write {}", tar_path.display()))
+}
+
+/// Package each plugin in plugins/pluginlist.json into a tar in plugins/dist, alongside the list
+/// of them that `analyse` and `aggregate` load. The base files for each interp in
+/// plugins/interplist.json are packaged too, for running scripts with.
+pub fn build_plugins() -> Result<()> {
+    let sources: BTreeMap<String, PluginSource> = read_source_list("pluginlist.json")?;
+    let interp_sources: BTreeMap<String, InterpSource> = read_source_list("interplist.json")?;
+
+    let dist_dir = Path::new(PLUGIN_DIST_DIR);
+    fs::create_dir_all(dist_dir).with_context(|| format!("failed to create {}", dist_dir.display()))?;
+    let registered = registered_versions()?;
+    let mut built = BTreeMap::new();
+    for (name, source) in sources {
+        if registered.contains_key(&name) {
+            bail!("there's already a registered plugin called {}", name)
+        }
+        wasm::check_plugin_info(&name, &source.info)?;
+        build_tar(&source.files, &dist_dir.join(format!("{}.tar", name)))
+            .with_context(|| format!("failed to build plugin {}", name))?;
+        info!("Built plugin {} from {} files", name, source.files.len());
+        built.insert(name, source.info);
+    }
+    let built = serde_json::to_vec(&built).expect("failed to serialize plugin list");
+    fs::write(wasm::PLUGIN_LIST_PATH, built).with_context(|| format!("failed to write {}", wasm::PLUGIN_LIST_PATH))?;
+
+    // Cleared out, so interps that were removed from the list don't linger
+    let base_dir = Path::new(INTERP_BASE_DIR);
+    if base_dir.is_dir() {
+        fs::remove_dir_all(base_dir).with_context(|| format!("failed to clear {}", base_dir.display()))?;
+    }
+    fs::create_dir_all(base_dir).with_context(|| format!("failed to create {}", base_dir.display()))?;
+    let mut interps = BTreeMap::new();
+    for (name, source) in interp_sources {
+        check_work_path(&source.info.script).with_context(|| format!("bad script path for interp {}", name))?;
+        build_tar(&source.files, &base_dir.join(format!("{}.tar", name)))
+            .with_context(|| format!("failed to build base files for interp {}", name))?;
+        info!("Built interp {} base from {} files", name, source.files.len());
+        interps.insert(name, source.info);
+    }
+    let interps = serde_json::to_vec(&interps).expect("failed to serialize interp list");
+    fs::write(INTERP_LIST_PATH, interps).with_context(|| format!("failed to write {}", INTERP_LIST_PATH))
+}
+
+/// The interps scripts can be run with - those in the interp list that have been built
+pub fn interp_list() -> Result<BTreeMap<String, InterpInfo>> {
+    let list_path = Path::new(INTERP_LIST_PATH);
+    if !list_path.is_file() {
+        warn!("No interp list at {}, scripts can't be run", list_path.display());
+        return Ok(BTreeMap::new())
+    }
+    let list = fs::read(list_path).with_context(|| format!("failed to read {}", list_path.display()))?;
+    let interps: BTreeMap<String, InterpInfo> = serde_json::from_slice(&list)
+        .with_context(|| format!("failed to parse {}", list_path.display()))?;
+    Ok(interps.into_iter().filter(|(name, _)| interp_path(name).is_file()).collect())
+}
+
+pub fn interp_path(interp: &str) -> PathBuf {
+    Path::new(PLUGIN_DIST_DIR).join(format!("{}.wasm", interp))
+}
+
+// Paths in /work have to stay in it, and tar paths are relative anyway
+fn check_work_path(path: &str) -> Result<()> {
+    let ok = !path.is_empty() && !path.starts_with('/') && !path.ends_with('/') &&
+        path.split('/').all(|component| !component.is_empty() && component != "." && component != "..");
+    if !ok {
+        bail!("{:?} isn't a relative path to a file", path)
+    }
+    Ok(())
+}
+
+/// A plugin for running a script with an interp: the interp's base files, any extra files (which
+/// can replace base files), and the script where the interp looks for it
+pub fn script_tar(interp: &str, script: &str, files: &BTreeMap<String, Vec<u8>>) -> Result<Vec<u8>> {
+    let info = interp_list()?.remove(interp).ok_or_else(|| anyhow!("unknown interp {}", interp))?;
+    let files_bytes: usize = files.values().map(Vec::len).sum();
+    if files_bytes > MAX_SCRIPT_FILES_BYTES {
+        bail!("extra files are {} bytes, more than the limit of {}", files_bytes, MAX_SCRIPT_FILES_BYTES)
+    }
+    for path in files.keys() {
+        check_work_path(path)?;
+        if *path == info.script {
+            bail!("extra file {} would replace the script", path)
+        }
+    }
+
+    let base_path = Path::new(INTERP_BASE_DIR).join(format!("{}.tar", interp));
+    let base_tar_data = fs::read(&base_path).with_context(|| format!("failed to read {}", base_path.display()))?;
+    let mut builder = tar::Builder::new(vec![]);
+    for entry in tar::Archive::new(&*base_tar_data).entries()? {
+        let mut entry = entry?;
+        let path = String::from_utf8(entry.path_bytes().into_owned()).context("non-utf8 path in interp base")?;
+        if path == info.script || files.contains_key(&path) {
+            continue
+        }
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+        append_file(&mut builder, &path, &data)?
+    }
+    for (path, data) in files.iter() {
+        append_file(&mut builder, path, data)?
+    }
+    append_file(&mut builder, &info.script, script.as_bytes())?;
+    Ok(builder.into_inner()?)
+}
+
+/// A plugin that can be run on songs - either built, or the latest version of a registered one
+pub struct PluginEntry {
+    pub info: PluginInfo,
+    /// Registered plugins start at 1, built plugins have no version
+    pub version: Option<u32>,
+    tar_path: PathBuf,
+}
+
+impl PluginEntry {
+    pub fn load(&self, name: &str) -> Result<AnalysisPlugin> {
+        wasm::load_plugin(name, &interp_path(&self.info.interp), &self.tar_path, self.info.level, self.info.outputs.clone())
+    }
+}
+
+/// Every plugin - the built ones (if they've been built) and the latest version of each
+/// registered one
+pub fn plugin_list() -> Result<BTreeMap<String, PluginEntry>> {
+    let list_path = Path::new(wasm::PLUGIN_LIST_PATH);
+    let mut plugins = BTreeMap::new();
+    if list_path.is_file() {
+        for (name, info) in wasm::load_plugin_list(list_path)? {
+            let tar_path = Path::new(PLUGIN_DIST_DIR).join(format!("{}.tar", name));
+            plugins.insert(name, PluginEntry { info, version: None, tar_path });
+        }
+    } else {
+        warn!("No plugin list at {}, only registered plugins are available", list_path.display());
+    }
+    for (name, version) in registered_versions()? {
+        // Registering checks for this, but a plugin with the name could have been built since
+        if plugins.contains_key(&name) {
+            warn!("Ignoring registered plugin {}, which has the same name as a built plugin", name);
+            continue
+        }
+        let dir = Path::new(REGISTERED_PLUGINS_DIR).join(&name);
+        let info_path = dir.join(format!("{}.json", version));
+        let info = fs::read(&info_path).with_context(|| format!("failed to read {}", info_path.display()))?;
+        let info: PluginInfo = serde_json::from_slice(&info).with_context(|| format!("failed to parse {}", info_path.display()))?;
+        wasm::check_plugin_info(&name, &info)?;
+        let tar_path = dir.join(format!("{}.tar", version));
+        plugins.insert(name, PluginEntry { info, version: Some(version), tar_path });
+    }
+    Ok(plugins)
+}
+
+// The latest version of each registered plugin. A version only counts once its info is written,
+// which happens last.
+fn registered_versions() -> Result<BTreeMap<String, u32>> {
+    let mut versions = BTreeMap::new();
+    let dir = Path::new(REGISTERED_PLUGINS_DIR);
+    if !dir.is_dir() {
+        return Ok(versions)
+    }
+    for entry in fs::read_dir(dir).with_context(|| format!("failed to list {}", dir.display()))? {
+        let entry = entry?;
+        let name = entry.file_name().into_string().map_err(|name| anyhow!("bad registered plugin name {:?}", name))?;
+        let latest = latest_version(&entry.path())?;
+        if let Some(version) = latest {
+            versions.insert(name, version);
+        }
+    }
+    Ok(versions)
+}
+
+fn latest_version(plugin_dir: &Path) -> Result<Option<u32>> {
+    if !plugin_dir.is_dir() {
+        return Ok(None)
+    }
+    let mut latest = None;
+    for entry in fs::read_dir(plugin_dir).with_context(|| format!("failed to list {}", plugin_dir.display()))? {
+        let file_name = entry?.file_name();
+        let version = file_name.to_str()
+            .and_then(|f| f.strip_suffix(".json"))
+            .and_then(|v| v.parse::<u32>().ok());
+        latest = latest.max(version);
+    }
+    Ok(latest)
+}
+
+/// Save a script as a new version of a plugin, returning the version. The plugin's results from
+/// earlier versions are thrown away, so `analyse` redoes them.
+pub fn register_plugin(conn: &SqliteConnection, name: &str, interp: &str, script: &str, level: PluginLevel, outputs: PluginSchema) -> Result<u32> {
+    // Names end up in paths and search attributes, which use - as a separator
+    if name.is_empty() || name.len() > 64 || !name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_') {
+        bail!("plugin names must be 1 to 64 lowercase letters, digits and underscores, not {:?}", name)
+    }
+    if level == PluginLevel::Aggregate {
+        bail!("aggregate plugins need building, they can't be registered")
+    }
+    let built = Path::new(wasm::PLUGIN_LIST_PATH);
+    if built.is_file() && wasm::load_plugin_list(built)?.contains_key(name) {
+        bail!("there's already a built plugin called {}", name)
+    }
+    let info = PluginInfo { interp: interp.to_owned(), level, group: None, inputs: None, outputs };
+    wasm::check_plugin_info(name, &info)?;
+    let tar_data = script_tar(interp, script, &BTreeMap::new())?;
+
+    let dir = Path::new(REGISTERED_PLUGINS_DIR).join(name);
+    fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    // Creating the tar claims the version, so registering twice at once can't pick the same one -
+    // whoever loses moves on to the next. A tar left behind by a failed register is skipped too.
+    let mut version = latest_version(&dir)?.unwrap_or(0) + 1;
+    let (tar_path, mut tar_file) = loop {
+        let tar_path = dir.join(format!("{}.tar", version));
+        match fs::OpenOptions::new().write(true).create_new(true).open(&tar_path) {
+            Ok(file) => break (tar_path, file),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => version += 1,
+            Err(e) => return Err(e).with_context(|| format!("failed to create {}", tar_path.display())),
+        }
+    };
+    tar_file.write_all(&tar_data).with_context(|| format!("failed to write {}", tar_path.display()))?;
+    let info_path = dir.join(format!("{}.json", version));
+    let info = serde_json::to_vec_pretty(&info).expect("failed to serialize plugin info");
+    // Renamed into place, so `plugin_list` never sees it half written
+    let tmp_path = dir.join(format!("{}.json.tmp", version));
+    fs::write(&tmp_path, info).with_context(|| format!("failed to write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, &info_path).with_context(|| format!("failed to rename {} to {}", tmp_path.display(), info_path.display()))?;
+    info!("Registered version {} of plugin {}", version, name);
+
+    if version > 1 {
+        let deleted = retry_busy(|| db::delete_song_analyses(conn, name.to_owned()))
+            .with_context(|| format!("registered version {} of {}, but failed to delete results from earlier versions", version, name))?;
+        info!("Deleted {} results from earlier versions of {}", deleted, name);
+    }
+    Ok(version)
+}
@@ -1,19 +1,17 @@
 /// This file is for on-off scripts that will not be used regularly
-use async_std::task;
 use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
 use std::thread;
-use sqlx::prelude::*;
-use sqlx::query;
 
-use super::INFO_PAUSE;
-use super::{key_to_num, num_to_key};
+use bsmeta::{key_to_num, num_to_key};
+use bsmeta::beatsaver::{INFO_PAUSE, get_map_meta, make_client};
+use bsmeta::db::{self, DbError, retry_busy};
 
 /// Validate that every song marked as deleted, is in fact deleted
 pub fn checkdeleted() {
-    let conn = &super::establish_connection();
-    let client = &super::make_client();
+    let conn = &db::establish_connection().expect("failed to connect to database");
+    let client = &make_client();
 
     fn load_deleteds() -> BTreeMap<String, bool> {
         if !Path::new("deleteds.json").is_file() {
@@ -27,10 +25,7 @@ pub fn checkdeleted() {
 
     println!("Loading all deleted songs from db");
     let mut deleteds = load_deleteds();
-    let currently_deleted = task::block_on(
-        query!("SELECT key FROM tSong WHERE deleted = true").fetch_all(conn)
-    ).expect("failed to select keys");
-    let currently_deleted: Vec<_> = currently_deleted.into_iter().map(|res| res.key).collect();
+    let currently_deleted = retry_busy(|| db::deleted_song_keys(conn)).expect("failed to select keys");
     let num_to_check = currently_deleted.len();
     println!("Checking {} deleted songs", num_to_check);
     for (i, key) in currently_deleted.into_iter().enumerate() {
@@ -39,7 +34,7 @@ pub fn checkdeleted() {
         if deleteds.contains_key(&key_str) {
             continue
         }
-        let is_deleted = super::get_map_meta(client, key).expect("failed to get map detail").is_none();
+        let is_deleted = get_map_meta(client, key).expect("failed to get map detail").is_none();
         assert!(deleteds.insert(key_str, is_deleted).is_none());
         save_deleteds(&deleteds);
 
@@ -54,27 +49,28 @@ pub fn checkdeleted() {
 
 /// Regenerate all extrameta and infodats
 pub fn regenzipderived() {
-    use super::zip_to_dats_tar;
+    use bsmeta::dats::zip_to_dats_tar;
 
-    let conn = &super::establish_connection();
+    let conn = &db::establish_connection().expect("failed to connect to database");
 
     println!("Finding all song data");
-    let needs_regenerating = task::block_on(query!("SELECT hash FROM tSongData").fetch_all(conn)).expect("failed to select hashes");
-    let needs_regenerating: Vec<_> = needs_regenerating.into_iter().map(|res| res.hash).collect();
+    let needs_regenerating = retry_busy(|| db::song_data_hashes(conn)).expect("failed to select hashes");
 
     let num_to_regenerate = needs_regenerating.len();
     println!("Regenerating data for {} songs", num_to_regenerate);
     for (i, hash) in needs_regenerating.into_iter().enumerate() {
         println!("Regenerating derived data for {} ({}/{})", hash, i+1, num_to_regenerate);
-        let zip = task::block_on(
-            query!("SELECT zipdata FROM tSongData WHERE hash = ?", hash).fetch_one(conn)
-        ).expect("failed to load zipdata").zipdata;
+        let zip = match retry_busy(|| db::get_song_zipdata(conn, &hash)) {
+            Ok(zip) => zip,
+            // It's possible for song data to vanish while this is running
+            Err(DbError::NotFound) => {
+                println!("Song data for {} went missing, skipping", hash);
+                continue
+            },
+            Err(e) => panic!("failed to load zipdata for {}: {}", hash, e),
+        };
         let (newdata, new_extra_meta) = zip_to_dats_tar(&zip).expect("failed to reprocess zip");
-        let res = task::block_on(query!("
-            UPDATE tSongData
-            SET data = ?, extra_meta = ?
-            WHERE hash = ?
-        ", newdata, new_extra_meta, hash).execute(conn)).expect("error saving data");
-        assert_eq!(res.rows_affected(), 1, "insert {}", hash)
+        retry_busy(|| db::update_song_derived_data(conn, hash.clone(), newdata.clone(), new_extra_meta.clone()))
+            .expect("error saving data")
     }
 }
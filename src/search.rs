@@ -0,0 +1,802 @@
+//! Keeping the search index in step with the database, and searching it
+//!
+//! Syncs are incremental - each song records which index it was pushed to and when, so only songs
+//! that have changed since get pushed again, and songs that have been deleted get removed. A
+//! rebuild builds a whole new index alongside the live one and only switches searches over to it
+//! (by updating the active index in the database) once it's complete.
+//!
+//! Indexes live in a [`SearchBackend`] - either meilisearch, or an embedded one in the database
+//! that needs no external service.
+use anyhow::{Context, Result, anyhow, bail};
+use async_std::task;
+use chrono::Utc;
+use log::{info, warn};
+use meilisearch_sdk::client::Client;
+use meilisearch_sdk::document::Document;
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::time;
+
+use super::dats::{CHARACTERISTICS, DIFFICULTIES};
+use super::db::{self, SqliteConnection, establish_connection, retry_busy};
+use super::models::BeatSaverMap;
+use super::num_to_key;
+use super::plugins;
+use super::wasm::{AnalysisValueType, PluginInfo, PluginLevel};
+
+const ID_KEY: &str = "key";
+const SEARCH_KEYS: &[&str] = &["name", "sub_name", "description"];
+const FILTER_KEYS: &[&str] = &[
+    "total_votes", "pct_upvoted", "uploaded_at_tstamp", "song_duration",
+    "has_easy", "has_normal", "has_hard", "has_expert", "has_expertplus",
+];
+const SORT_KEYS: &[&str] = &["total_votes", "pct_upvoted", "uploaded_at_tstamp", "song_duration"];
+const FACET_KEYS: &[&str] = &["uploader"];
+const FACET_GROUP_KEYS: &[&str] = &["modes"];
+const _VIEW_KEYS: &[&str] = &[];
+
+// The index used before the active index was tracked in the database
+const LEGACY_INDEX: &str = "songs";
+
+const BATCH_SIZE: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug)]
+pub struct SearchSong {
+    // ID
+    pub key: String,
+    // Not searched, just for finding the song data
+    pub hash: String,
+
+    // Search keys
+    pub name: String,
+    pub sub_name: String,
+    // TODO: use description from bsaber.com instead
+    pub description: String,
+
+    // Filter keys
+    pub total_votes: u32,
+    pub pct_upvoted: u8,
+    pub uploaded_at_tstamp: i64,
+    /// In seconds, if we have the song data and could work it out
+    pub song_duration: Option<f32>,
+    // Whether any characteristic has each difficulty
+    pub has_easy: bool,
+    pub has_normal: bool,
+    pub has_hard: bool,
+    pub has_expert: bool,
+    pub has_expertplus: bool,
+    // TODO: categories from bsaber.com?
+
+    // Facet keys
+    pub uploader: String,
+
+    // Facet group keys
+    pub modes: Vec<String>,
+    // TODO: categories from bsaber.com
+
+    // Analysis results, named by `analysis_attribute`. Booleans and numbers can also be filtered
+    // on, and numbers sorted on - arrays and objects are stored as they are, for display only
+    #[serde(flatten)]
+    pub analyses: HashMap<String, serde_json::Value>,
+    // TODO: bsaber.com post id
+}
+
+/// Which attributes of songs can be filtered and sorted on
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct SearchAttributes {
+    pub filterable: BTreeSet<String>,
+    pub sortable: BTreeSet<String>,
+}
+
+/// The plugins whose results are described in the index - built ones, if they've been built, and
+/// registered ones
+pub fn indexed_plugins() -> Result<BTreeMap<String, PluginInfo>> {
+    Ok(plugins::plugin_list()?.into_iter().map(|(name, entry)| (name, entry.info)).collect())
+}
+
+/// The name of an analysis result in the index - `plugin-key` for results about the whole map, and
+/// `plugin-Characteristic-Difficulty-key` (like `parity-Standard-ExpertPlus-num_errors`) for
+/// results about a single beatmap
+pub fn analysis_attribute(plugin_name: &str, beatmap: Option<(&str, &str)>, key: &str) -> String {
+    match beatmap {
+        None => format!("{}-{}", plugin_name, key),
+        Some((characteristic, difficulty)) => format!("{}-{}-{}-{}", plugin_name, characteristic, difficulty, key),
+    }
+}
+
+/// Work out the attributes songs can be filtered and sorted on. As well as the fixed ones, any
+/// boolean a plugin declares in its output schema is filterable, and any number is filterable and
+/// sortable. For beatmap plugins that's per beatmap, plus the aggregated result for the map.
+pub fn search_attributes(plugins: &BTreeMap<String, PluginInfo>) -> SearchAttributes {
+    let mut attributes = SearchAttributes {
+        filterable: FILTER_KEYS.iter().chain(FACET_KEYS).chain(FACET_GROUP_KEYS).map(|&s| s.to_owned()).collect(),
+        sortable: SORT_KEYS.iter().map(|&s| s.to_owned()).collect(),
+    };
+    for (plugin_name, info) in plugins {
+        for (key, output) in &info.outputs {
+            let mut names = vec![];
+            match info.level {
+                PluginLevel::Map | PluginLevel::Aggregate => names.push(analysis_attribute(plugin_name, None, key)),
+                PluginLevel::Beatmap => {
+                    if output.aggregate().is_some() {
+                        names.push(analysis_attribute(plugin_name, None, key))
+                    }
+                    for &characteristic in CHARACTERISTICS {
+                        for &difficulty in DIFFICULTIES {
+                            names.push(analysis_attribute(plugin_name, Some((characteristic, difficulty)), key))
+                        }
+                    }
+                },
+            }
+            for attribute in names {
+                if !Filter::valid_attribute(&attribute) {
+                    warn!("Analysis result {} can't be filtered on, its name has unsupported characters", attribute);
+                    continue
+                }
+                match output.value_type {
+                    AnalysisValueType::Bool => (),
+                    AnalysisValueType::Number => { attributes.sortable.insert(attribute.clone()); },
+                    AnalysisValueType::String |
+                    AnalysisValueType::Array |
+                    AnalysisValueType::Object => continue,
+                }
+                attributes.filterable.insert(attribute);
+            }
+        }
+    }
+    attributes
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FilterOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl FilterOp {
+    // Longest first, so e.g. <= isn't taken as <
+    const ALL: &'static [FilterOp] = &[FilterOp::Ne, FilterOp::Le, FilterOp::Ge, FilterOp::Eq, FilterOp::Lt, FilterOp::Gt];
+
+    /// The operator as written in filters - which is the same for meilisearch and SQL
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterOp::Eq => "=",
+            FilterOp::Ne => "!=",
+            FilterOp::Lt => "<",
+            FilterOp::Le => "<=",
+            FilterOp::Gt => ">",
+            FilterOp::Ge => ">=",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterValue {
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+impl fmt::Display for FilterValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilterValue::Bool(b) => write!(f, "{}", b),
+            FilterValue::Number(n) => write!(f, "{}", n),
+            FilterValue::String(s) => write!(f, "\"{}\"", s),
+        }
+    }
+}
+
+/// A condition on an attribute, like `song_duration < 180`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub attribute: String,
+    pub op: FilterOp,
+    pub value: FilterValue,
+}
+
+impl Filter {
+    fn valid_attribute(attribute: &str) -> bool {
+        !attribute.is_empty() && attribute.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    }
+
+    /// Parse a comma separated list of filters, like `has_expertplus = true, pct_upvoted > 90`.
+    /// Commas in quoted values don't separate filters.
+    pub fn parse_list(s: &str) -> Result<Vec<Filter>> {
+        let mut filters = vec![];
+        let mut start = 0;
+        let mut quoted = false;
+        for (i, c) in s.char_indices() {
+            match c {
+                '"' => quoted = !quoted,
+                ',' if !quoted => {
+                    filters.push(&s[start..i]);
+                    start = i + 1
+                },
+                _ => (),
+            }
+        }
+        filters.push(&s[start..]);
+        filters.into_iter().filter(|f| !f.trim().is_empty()).map(str::parse).collect()
+    }
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (pos, op) = s.char_indices()
+            .find_map(|(i, _)| FilterOp::ALL.iter().find(|op| s[i..].starts_with(op.as_str())).map(|&op| (i, op)))
+            .ok_or_else(|| anyhow!("no comparison in filter {:?}", s))?;
+        let attribute = s[..pos].trim();
+        let value = s[pos + op.as_str().len()..].trim();
+        if !Filter::valid_attribute(attribute) {
+            bail!("invalid attribute in filter {:?}", s)
+        }
+        let value = match value {
+            "true" => FilterValue::Bool(true),
+            "false" => FilterValue::Bool(false),
+            v => match v.parse::<f64>() {
+                Ok(n) if n.is_finite() => FilterValue::Number(n),
+                _ => {
+                    let v = v.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(v);
+                    if v.is_empty() || v.contains('"') {
+                        bail!("invalid value in filter {:?}", s)
+                    }
+                    FilterValue::String(v.to_owned())
+                },
+            },
+        };
+        Ok(Filter { attribute: attribute.to_owned(), op, value })
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.attribute, self.op.as_str(), self.value)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Sort {
+    pub attribute: String,
+    pub descending: bool,
+}
+
+impl FromStr for Sort {
+    type Err = anyhow::Error;
+    /// Parse a sort like `pct_upvoted:desc` - ascending if no direction is given
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (attribute, descending) = match s.rfind(':').map(|i| (&s[..i], &s[i+1..])) {
+            Some((attribute, "asc")) => (attribute, false),
+            Some((attribute, "desc")) => (attribute, true),
+            Some(_) => bail!("invalid sort direction in {:?}", s),
+            None => (s, false),
+        };
+        if !Filter::valid_attribute(attribute) {
+            bail!("invalid attribute in sort {:?}", s)
+        }
+        Ok(Sort { attribute: attribute.to_owned(), descending })
+    }
+}
+
+impl fmt::Display for Sort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.attribute, if self.descending { "desc" } else { "asc" })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchQuery {
+    /// Matched against names, descriptions and so on - may be empty to just filter
+    pub text: String,
+    /// Songs must match all of these
+    pub filters: Vec<Filter>,
+    pub sort: Option<Sort>,
+    pub limit: usize,
+}
+
+impl SearchQuery {
+    /// Check the query only filters and sorts on attributes that allow it - otherwise meilisearch
+    /// fails the search, and the embedded backend just matches nothing
+    pub fn check_attributes(&self, attributes: &SearchAttributes) -> Result<()> {
+        for filter in &self.filters {
+            if !attributes.filterable.contains(&filter.attribute) {
+                bail!("{} can't be filtered on", filter.attribute)
+            }
+        }
+        if let Some(sort) = &self.sort {
+            if !attributes.sortable.contains(&sort.attribute) {
+                bail!("{} can't be sorted on", sort.attribute)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Somewhere to keep songs for searching. Index names are chosen by the caller and opaque to the
+/// backend, which just needs to keep different indexes separate.
+pub trait SearchBackend {
+    /// Identifies the backend in the database, so each backend has its own active index
+    fn name(&self) -> &'static str;
+    /// Create an empty index, set up for searching songs
+    fn create_index(&self, index_name: &str) -> Result<()>;
+    /// Make sure the attributes can be filtered and sorted on - this should be cheap if nothing has
+    /// changed
+    fn set_attributes(&self, index_name: &str, attributes: &SearchAttributes) -> Result<()>;
+    /// Delete an index and everything in it - it's fine if it doesn't exist
+    fn delete_index(&self, index_name: &str) -> Result<()>;
+    /// Add songs to an index, replacing any already there with the same key
+    fn add_or_replace(&self, index_name: &str, songs: &[SearchSong]) -> Result<()>;
+    /// Remove songs from an index by key - it's fine if they aren't there
+    fn delete(&self, index_name: &str, keys: &[String]) -> Result<()>;
+    /// Songs matching a query, best matches first unless the query says how to sort
+    fn search(&self, index_name: &str, query: &SearchQuery) -> Result<Vec<SearchSong>>;
+}
+
+/// The backend chosen by `SEARCH_BACKEND` (`meili` or `sqlite`). If that's not set, meilisearch is
+/// used if `MEILI_URL` is set and the embedded backend otherwise.
+pub fn backend_from_env(conn: &SqliteConnection) -> Result<Box<dyn SearchBackend>> {
+    let backend = match env::var("SEARCH_BACKEND") {
+        Ok(backend) => backend,
+        Err(env::VarError::NotPresent) if env::var_os("MEILI_URL").is_some() => MeiliBackend::NAME.to_owned(),
+        Err(env::VarError::NotPresent) => SqliteBackend::NAME.to_owned(),
+        Err(e) => bail!("invalid SEARCH_BACKEND: {}", e),
+    };
+    Ok(match backend.as_str() {
+        MeiliBackend::NAME => Box::new(MeiliBackend {
+            url: env::var("MEILI_URL").context("no meili url")?,
+            key: env::var("MEILI_PRIVATEKEY").context("no meili masterkey")?,
+        }),
+        SqliteBackend::NAME => Box::new(SqliteBackend { conn: conn.clone() }),
+        other => bail!("unknown search backend {}", other),
+    })
+}
+
+/// Search the active index of the configured backend
+pub fn search_songs(conn: &SqliteConnection, query: &SearchQuery) -> Result<Vec<SearchSong>> {
+    let backend = backend_from_env(conn)?;
+    let index_name = retry_busy(|| db::get_active_search_index(conn, backend.name()))
+        .context("failed to get active search index")?
+        .ok_or_else(|| anyhow!("no {} search index has been built yet", backend.name()))?;
+    backend.search(&index_name, query)
+}
+
+pub struct MeiliBackend {
+    url: String,
+    key: String,
+}
+
+impl MeiliBackend {
+    const NAME: &'static str = "meili";
+
+    fn client(&self) -> Client<'_> {
+        Client::new(&self.url, &self.key)
+    }
+}
+
+impl Document for SearchSong {
+    type UIDType = String;
+    fn get_uid(&self) -> &Self::UIDType { &self.key }
+}
+
+async fn wait_progress_complete(progress: meilisearch_sdk::progress::Progress) -> Result<()> {
+    loop {
+        match progress.get_status().await.context("meilisearch returned error")? {
+            meilisearch_sdk::progress::UpdateStatus::Processed { content } => {
+                if let Some(error) = content.error {
+                    bail!("meilisearch update failed: {}", error)
+                }
+                return Ok(())
+            },
+            meilisearch_sdk::progress::UpdateStatus::Failed { content } => {
+                bail!("meilisearch update failed: {:?}", content)
+            }
+            meilisearch_sdk::progress::UpdateStatus::Processing { content: _ } |
+            meilisearch_sdk::progress::UpdateStatus::Enqueued { content: _ } => {
+                task::sleep(time::Duration::from_secs(1)).await
+            },
+        }
+    }
+}
+
+impl SearchBackend for MeiliBackend {
+    fn name(&self) -> &'static str { Self::NAME }
+
+    fn create_index(&self, index_name: &str) -> Result<()> {
+        task::block_on(async {
+            let idx = self.client().create_index(index_name, Some(ID_KEY)).await.context("failed to create index")?;
+
+            let searchable_attributes = SEARCH_KEYS.iter().chain(FACET_KEYS).chain(FACET_GROUP_KEYS)
+                .map(|&s| s.to_owned())
+                .collect();
+            let progress = idx.set_settings(&meilisearch_sdk::settings::Settings {
+                synonyms: None,
+                stop_words: None,
+                ranking_rules: None,
+                filterable_attributes: None,
+                sortable_attributes: None,
+                distinct_attribute: None,
+                searchable_attributes: Some(searchable_attributes),
+                displayed_attributes: Some(vec!["*".to_owned()]),
+            }).await.context("failed to set settings")?;
+            info!("Waiting for meilisearch to apply index settings");
+            wait_progress_complete(progress).await
+        })
+    }
+
+    fn set_attributes(&self, index_name: &str, attributes: &SearchAttributes) -> Result<()> {
+        task::block_on(async {
+            let idx = self.client().get_index(index_name).await.context("failed to get index")?;
+            // Changing settings makes meilisearch reindex everything, so avoid it if possible
+            let current = idx.get_settings().await.context("failed to get settings")?;
+            let current = SearchAttributes {
+                filterable: current.filterable_attributes.unwrap_or_default().into_iter().collect(),
+                sortable: current.sortable_attributes.unwrap_or_default().into_iter().collect(),
+            };
+            if &current == attributes {
+                return Ok(())
+            }
+            let progress = idx.set_settings(&meilisearch_sdk::settings::Settings {
+                synonyms: None,
+                stop_words: None,
+                ranking_rules: None,
+                filterable_attributes: Some(attributes.filterable.iter().cloned().collect()),
+                sortable_attributes: Some(attributes.sortable.iter().cloned().collect()),
+                distinct_attribute: None,
+                searchable_attributes: None,
+                displayed_attributes: None,
+            }).await.context("failed to set settings")?;
+            info!("Waiting for meilisearch to apply filterable and sortable attributes");
+            wait_progress_complete(progress).await
+        })
+    }
+
+    fn delete_index(&self, index_name: &str) -> Result<()> {
+        match task::block_on(self.client().delete_index(index_name)) {
+            Ok(()) => Ok(()),
+            Err(meilisearch_sdk::errors::Error::MeiliSearchError { error_code: meilisearch_sdk::errors::ErrorCode::IndexNotFound, .. }) => Ok(()),
+            Err(e) => Err(e).context("failed to delete index"),
+        }
+    }
+
+    fn add_or_replace(&self, index_name: &str, songs: &[SearchSong]) -> Result<()> {
+        task::block_on(async {
+            let idx = self.client().get_index(index_name).await.context("failed to get index")?;
+            let progress = idx.add_or_replace(songs, Some(ID_KEY)).await.context("failed to send songs for addition")?;
+            wait_progress_complete(progress).await
+        })
+    }
+
+    fn delete(&self, index_name: &str, keys: &[String]) -> Result<()> {
+        task::block_on(async {
+            let idx = self.client().get_index(index_name).await.context("failed to get index")?;
+            let progress = idx.delete_documents(keys).await.context("failed to send songs for deletion")?;
+            wait_progress_complete(progress).await
+        })
+    }
+
+    fn search(&self, index_name: &str, query: &SearchQuery) -> Result<Vec<SearchSong>> {
+        task::block_on(async {
+            let idx = self.client().get_index(index_name).await.context("failed to get index")?;
+            let filter = query.filters.iter().map(|f| f.to_string()).collect::<Vec<_>>().join(" AND ");
+            let sort = query.sort.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            let sort: Vec<&str> = sort.iter().map(|s| s.as_str()).collect();
+            let mut search = idx.search();
+            search.with_query(&query.text).with_limit(query.limit);
+            if !filter.is_empty() {
+                search.with_filter(&filter);
+            }
+            if !sort.is_empty() {
+                search.with_sort(&sort);
+            }
+            let results = search.execute::<SearchSong>().await.context("failed to search")?;
+            Ok(results.hits.into_iter().map(|hit| hit.result).collect())
+        })
+    }
+}
+
+/// Search backed by an FTS5 table in the database
+pub struct SqliteBackend {
+    conn: SqliteConnection,
+}
+
+impl SqliteBackend {
+    const NAME: &'static str = "sqlite";
+}
+
+impl SearchBackend for SqliteBackend {
+    fn name(&self) -> &'static str { Self::NAME }
+
+    // Indexes are just a column on the documents table, so there's nothing to set up
+    fn create_index(&self, _index_name: &str) -> Result<()> {
+        Ok(())
+    }
+
+    // Filters and sorts can use any attribute, since they're done on the JSON of the documents
+    fn set_attributes(&self, _index_name: &str, _attributes: &SearchAttributes) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete_index(&self, index_name: &str) -> Result<()> {
+        retry_busy(|| db::delete_search_index_docs(&self.conn, index_name)).context("failed to delete index")
+    }
+
+    fn add_or_replace(&self, index_name: &str, songs: &[SearchSong]) -> Result<()> {
+        let docs = songs.iter().map(|song| Ok(db::SearchDoc {
+            key: song.key.clone(),
+            name: song.name.clone(),
+            sub_name: song.sub_name.clone(),
+            description: song.description.clone(),
+            uploader: song.uploader.clone(),
+            modes: song.modes.join(" "),
+            doc: serde_json::to_vec(song)?,
+        })).collect::<Result<Vec<_>>>()?;
+        retry_busy(|| db::upsert_search_docs(&self.conn, index_name.to_owned(), docs.clone()))
+            .context("failed to add songs")
+    }
+
+    fn delete(&self, index_name: &str, keys: &[String]) -> Result<()> {
+        retry_busy(|| db::delete_search_docs(&self.conn, index_name.to_owned(), keys.to_vec()))
+            .context("failed to delete songs")
+    }
+
+    fn search(&self, index_name: &str, query: &SearchQuery) -> Result<Vec<SearchSong>> {
+        // Treat the text as plain words rather than FTS5 syntax, each of which must prefix-match
+        let fts_query: Vec<String> = query.text.split_whitespace()
+            .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+            .collect();
+        let fts_query = if fts_query.is_empty() { None } else { Some(fts_query.join(" ")) };
+        let limit = i64::try_from(query.limit).unwrap_or(i64::MAX);
+        let docs = retry_busy(|| db::search_docs(&self.conn, index_name, fts_query.as_deref(), &query.filters, query.sort.as_ref(), limit))
+            .context("failed to search")?;
+        docs.into_iter()
+            .map(|doc| serde_json::from_slice(&doc).context("failed to deserialize search document"))
+            .collect()
+    }
+}
+
+/// Bring the search index of the configured backend up to date, or with `rebuild`, build a fresh
+/// one and switch to it. A rebuild is also needed to pick up changes to the index settings.
+pub fn update_search(rebuild: bool) {
+    let conn = &establish_connection().expect("failed to connect to database");
+    let backend = backend_from_env(conn).expect("failed to set up search backend");
+    let backend = &*backend;
+
+    let active = retry_busy(|| db::get_active_search_index(conn, backend.name())).expect("failed to get active search index");
+    match (active, rebuild) {
+        (Some(index_name), false) => sync_index(conn, backend, &index_name),
+        (active, _) => rebuild_index(conn, backend, active),
+    }
+}
+
+fn sync_index(conn: &SqliteConnection, backend: &dyn SearchBackend, index_name: &str) {
+    // Taken before looking at the database, so anything that changes during the sync gets picked
+    // up by the next one
+    let sync_tstamp = Utc::now().timestamp_millis();
+    println!("Updating {} search index {}", backend.name(), index_name);
+
+    let to_remove = retry_busy(|| db::songs_to_unindex(conn, index_name)).expect("failed to select deleted songs");
+    println!("Removing {} deleted songs", to_remove.len());
+    for keys in to_remove.chunks(BATCH_SIZE) {
+        let key_strs: Vec<String> = keys.iter().map(|&key| num_to_key(key)).collect();
+        backend.delete(index_name, &key_strs).expect("failed to delete batch of songs");
+        retry_busy(|| db::unmark_search_indexed(conn, index_name.to_owned(), keys.to_vec()))
+            .expect("failed to unmark deleted songs");
+    }
+
+    let plugins = indexed_plugins().expect("failed to load plugin list");
+    let attributes = search_attributes(&plugins);
+    backend.set_attributes(index_name, &attributes).expect("failed to set search attributes");
+
+    push_songs(conn, backend, index_name, sync_tstamp);
+}
+
+fn rebuild_index(conn: &SqliteConnection, backend: &dyn SearchBackend, old_index_name: Option<String>) {
+    let sync_tstamp = Utc::now().timestamp_millis();
+    let index_name = format!("{}-songs-{}", backend.name(), sync_tstamp);
+    println!("Building new {} search index {}", backend.name(), index_name);
+
+    backend.create_index(&index_name).expect("failed to create index");
+    let plugins = indexed_plugins().expect("failed to load plugin list");
+    let attributes = search_attributes(&plugins);
+    backend.set_attributes(&index_name, &attributes).expect("failed to set search attributes");
+    // Nothing is marked as being in the new index, so this pushes every live song
+    push_songs(conn, backend, &index_name, sync_tstamp);
+
+    retry_busy(|| db::set_active_search_index(conn, backend.name(), &index_name)).expect("failed to switch active search index");
+    println!("Switched searches to {}", index_name);
+
+    let old_index_name = old_index_name.unwrap_or_else(|| LEGACY_INDEX.to_owned());
+    backend.delete_index(&old_index_name).unwrap_or_else(|e| panic!("failed to delete old index {}: {}", old_index_name, e));
+    retry_busy(|| db::clear_search_indexed(conn, &old_index_name)).expect("failed to clear old index markers");
+    println!("Deleted old search index {}", old_index_name);
+}
+
+fn push_songs(conn: &SqliteConnection, backend: &dyn SearchBackend, index_name: &str, sync_tstamp: i64) {
+    let songs = retry_busy(|| db::songs_to_index(conn, index_name)).expect("failed to select songs");
+    let num_songs = songs.len();
+    println!("Pushing {} new or changed songs", num_songs);
+
+    let mut batch = vec![];
+    let mut batch_keys = vec![];
+    for (i, (key, hash)) in songs.into_iter().enumerate() {
+        info!("Considering song {}/{}: {}", i+1, num_songs, num_to_key(key));
+        if let Some(song) = search_song(conn, key, &hash) {
+            batch.push(song);
+            batch_keys.push(key)
+        }
+        if batch.len() == BATCH_SIZE {
+            push_batch(conn, backend, index_name, sync_tstamp, &mut batch, &mut batch_keys)
+        }
+    }
+    if !batch.is_empty() {
+        push_batch(conn, backend, index_name, sync_tstamp, &mut batch, &mut batch_keys)
+    }
+}
+
+fn push_batch(conn: &SqliteConnection, backend: &dyn SearchBackend, index_name: &str, sync_tstamp: i64, batch: &mut Vec<SearchSong>, batch_keys: &mut Vec<i64>) {
+    backend.add_or_replace(index_name, batch).expect("failed to add batch of songs");
+    retry_busy(|| db::mark_search_indexed(conn, index_name.to_owned(), batch_keys.clone(), sync_tstamp))
+        .expect("failed to mark songs as indexed");
+    batch.clear();
+    batch_keys.clear()
+}
+
+fn search_song(conn: &SqliteConnection, key: i64, hash: &str) -> Option<SearchSong> {
+    let key_str = num_to_key(key);
+    let song_meta = match retry_busy(|| db::get_song_meta(conn, key)).expect("failed to load song meta") {
+        Some(sm) => sm,
+        None => {
+            warn!("Song {} went missing from db, skipping", key_str);
+            return None
+        },
+    };
+    let bsmeta: BeatSaverMap = serde_json::from_slice(&song_meta.bsmeta).expect("failed to deserialize bsmeta");
+
+    let mut analyses = HashMap::new();
+    let analysis_results = retry_busy(|| db::get_song_analyses(conn, hash)).expect("failed to retrieve analyses");
+    for (analysis_name, result) in analysis_results {
+        let analysis_results_map: HashMap<String, serde_json::Value> = serde_json::from_slice(&result).expect("couldn't parse analysis result");
+        // Prefix results with plugin
+        analyses.extend(analysis_results_map.into_iter().map(|(k, v)| (analysis_attribute(&analysis_name, None, &k), v)));
+    }
+    let beatmap_results = retry_busy(|| db::get_song_beatmap_analyses(conn, hash)).expect("failed to retrieve beatmap analyses");
+    for (analysis_name, characteristic, difficulty, result) in beatmap_results {
+        let analysis_results_map: HashMap<String, serde_json::Value> = serde_json::from_slice(&result).expect("couldn't parse beatmap analysis result");
+        let beatmap = Some((characteristic.as_str(), difficulty.as_str()));
+        analyses.extend(analysis_results_map.into_iter().map(|(k, v)| (analysis_attribute(&analysis_name, beatmap, &k), v)));
+    }
+    let aggregate_results = retry_busy(|| db::get_song_aggregates(conn, key)).expect("failed to retrieve aggregates");
+    for (analysis_name, result) in aggregate_results {
+        let analysis_results_map: HashMap<String, serde_json::Value> = serde_json::from_slice(&result).expect("couldn't parse aggregate result");
+        analyses.extend(analysis_results_map.into_iter().map(|(k, v)| (analysis_attribute(&analysis_name, None, &k), v)));
+    }
+
+    let total_votes = bsmeta.stats.upvotes + bsmeta.stats.downvotes;
+    let pct_upvoted = if total_votes == 0 { 100. } else { (100. * f64::from(bsmeta.stats.upvotes) / f64::from(total_votes)).round() };
+    assert!(0. <= pct_upvoted && pct_upvoted <= 100.);
+    let pct_upvoted = pct_upvoted as u8;
+    let extra_meta = retry_busy(|| db::get_song_extra_meta(conn, hash)).expect("failed to load extra meta");
+    let song_duration = extra_meta.and_then(|em| em.song_duration).map(|d| d.into_inner());
+
+    let diffs = &bsmeta.current_version().diffs;
+    let modes = diffs.iter().map(|d| d.characteristic.clone()).collect();
+    let has_difficulty = |difficulty: &str| diffs.iter().any(|d| d.difficulty == difficulty);
+    let (has_easy, has_normal, has_hard, has_expert, has_expertplus) = (
+        has_difficulty("Easy"), has_difficulty("Normal"), has_difficulty("Hard"), has_difficulty("Expert"), has_difficulty("ExpertPlus"),
+    );
+    Some(SearchSong {
+        key: key_str,
+        hash: hash.to_owned(),
+        name: bsmeta.metadata.song_name,
+        sub_name: bsmeta.metadata.song_sub_name,
+        description: bsmeta.description,
+        total_votes,
+        pct_upvoted,
+        uploaded_at_tstamp: bsmeta.uploaded.timestamp(),
+        song_duration,
+        has_easy,
+        has_normal,
+        has_hard,
+        has_expert,
+        has_expertplus,
+        uploader: bsmeta.uploader.name,
+        modes,
+        analyses,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(attribute: &str, op: FilterOp, value: FilterValue) -> Filter {
+        Filter { attribute: attribute.to_owned(), op, value }
+    }
+
+    #[test]
+    fn filter_ops() {
+        let cases = [
+            ("song_duration <= 180", FilterOp::Le),
+            ("song_duration<180", FilterOp::Lt),
+            ("song_duration >= 180", FilterOp::Ge),
+            ("song_duration>180", FilterOp::Gt),
+            ("song_duration != 180", FilterOp::Ne),
+            ("song_duration = 180", FilterOp::Eq),
+        ];
+        for &(s, op) in cases.iter() {
+            assert_eq!(s.parse::<Filter>().unwrap(), filter("song_duration", op, FilterValue::Number(180.0)), "{}", s);
+        }
+    }
+
+    #[test]
+    fn filter_values() {
+        assert_eq!("has_easy = true".parse::<Filter>().unwrap(), filter("has_easy", FilterOp::Eq, FilterValue::Bool(true)));
+        assert_eq!("pct_upvoted > 89.5".parse::<Filter>().unwrap(), filter("pct_upvoted", FilterOp::Gt, FilterValue::Number(89.5)));
+        assert_eq!("uploader = bob".parse::<Filter>().unwrap(), filter("uploader", FilterOp::Eq, FilterValue::String("bob".to_owned())));
+        assert_eq!(r#"uploader = "bob smith""#.parse::<Filter>().unwrap(), filter("uploader", FilterOp::Eq, FilterValue::String("bob smith".to_owned())));
+        // Only finite numbers are numbers, anything else is a string
+        assert_eq!("song_duration < inf".parse::<Filter>().unwrap(), filter("song_duration", FilterOp::Lt, FilterValue::String("inf".to_owned())));
+        assert_eq!("song_duration = NaN".parse::<Filter>().unwrap(), filter("song_duration", FilterOp::Eq, FilterValue::String("NaN".to_owned())));
+    }
+
+    #[test]
+    fn filter_invalid() {
+        for s in ["song_duration 180", "= 180", "song duration = 180", "uploader = ", r#"uploader = "a"b""#, r#"uploader = """#].iter() {
+            assert!(s.parse::<Filter>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn filter_display() {
+        assert_eq!(filter("song_duration", FilterOp::Le, FilterValue::Number(180.0)).to_string(), "song_duration <= 180");
+        assert_eq!(filter("uploader", FilterOp::Ne, FilterValue::String("bob".to_owned())).to_string(), r#"uploader != "bob""#);
+    }
+
+    #[test]
+    fn filter_list() {
+        let filters = Filter::parse_list(r#"uploader = "smith, bob", has_easy = true,, "#).unwrap();
+        assert_eq!(filters, vec![
+            filter("uploader", FilterOp::Eq, FilterValue::String("smith, bob".to_owned())),
+            filter("has_easy", FilterOp::Eq, FilterValue::Bool(true)),
+        ]);
+        assert_eq!(Filter::parse_list("").unwrap(), vec![]);
+        assert!(Filter::parse_list(r#"uploader = "smith, has_easy = true"#).is_err());
+    }
+
+    #[test]
+    fn sort() {
+        assert_eq!("pct_upvoted:desc".parse::<Sort>().unwrap(), Sort { attribute: "pct_upvoted".to_owned(), descending: true });
+        assert_eq!(" song_duration:asc ".parse::<Sort>().unwrap(), Sort { attribute: "song_duration".to_owned(), descending: false });
+        assert_eq!("song_duration".parse::<Sort>().unwrap(), Sort { attribute: "song_duration".to_owned(), descending: false });
+        assert_eq!("parity-Standard-Expert-num_errors:desc".parse::<Sort>().unwrap().to_string(), "parity-Standard-Expert-num_errors:desc");
+        for s in ["song_duration:up", ":desc", "a:b:desc", "song duration"].iter() {
+            assert!(s.parse::<Sort>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn query_attributes() {
+        let attributes = search_attributes(&BTreeMap::new());
+        let query = |filters: Vec<Filter>, sort: Option<&str>| SearchQuery {
+            text: String::new(),
+            filters,
+            sort: sort.map(|s| s.parse().unwrap()),
+            limit: 10,
+        };
+        let uploader = filter("uploader", FilterOp::Eq, FilterValue::String("bob".to_owned()));
+        assert!(query(vec![uploader.clone()], Some("song_duration")).check_attributes(&attributes).is_ok());
+        assert!(query(vec![], Some("uploader")).check_attributes(&attributes).is_err());
+        assert!(query(vec![filter("nope", FilterOp::Eq, FilterValue::Bool(true))], None).check_attributes(&attributes).is_err());
+    }
+}
@@ -1,14 +1,37 @@
+use anyhow::{Context, bail};
+use async_std::channel::{self, Sender};
+use async_std::future;
 use async_std::task;
-use std::convert::TryInto;
-use std::fs;
-use sqlx::query;
+use serde_json::Value;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 use tide::{Body, Request, StatusCode};
 use tide::prelude::*;
+use tide::sse;
 
-use super::BeatSaverMap;
-use super::{establish_connection, load_dats_for_analysis, num_to_key};
+use bsmeta::{dats, fingerprint, key_to_num, num_to_key, parse_key, plugins, search, wasm};
+use bsmeta::db::{self, DbError};
+use bsmeta::models::BeatSaverMap;
+use bsmeta::search::{Filter, SearchQuery, Sort};
 
-//async fn index(req: Request<()>) -> tide::Result {
+// The database is only a web UI convenience, so don't keep requests hanging around retrying
+fn db_error(e: DbError) -> tide::Error {
+    let status = match e {
+        DbError::NotFound => StatusCode::NotFound,
+        DbError::Busy(_) => StatusCode::ServiceUnavailable,
+        _ => StatusCode::InternalServerError,
+    };
+    tide::Error::from_str(status, e.to_string())
+}
+
+//async fn index(req: Request<State>) -> tide::Result {
 //    let mut res: tide::Response = "\
 //<html>
 //<head>
@@ -22,84 +45,518 @@ use super::{establish_connection, load_dats_for_analysis, num_to_key};
 //    Ok(res)
 //}
 
-async fn api(_req: Request<()>) -> tide::Result {
-    let conn = &establish_connection();
-    let results: Vec<_> = query!("
-        SELECT s.key, sm.hash, sm.bsmeta
-        FROM tSong s, tSongMeta sm, tSongData sd
-        WHERE
-            s.deleted = false AND
-            s.key = sm.key AND
-            sm.hash = sd.hash
-        ORDER BY s.key DESC
-        LIMIT 100
-    ").fetch_all(conn).await.unwrap();
-    let results: Vec<(String, String, String)> = results.into_iter()
-        .map(|result| {
-            let bsmeta: BeatSaverMap = serde_json::from_slice(&result.bsmeta).unwrap();
-            (num_to_key(result.key), result.hash, format!("{} {}", bsmeta.metadata.song_name, bsmeta.metadata.song_sub_name))
-        })
-        .collect();
+const API_LIMIT: usize = 100;
+
+// With a text query `q`, comma separated `filter`s (like `has_expertplus = true, song_duration < 180`)
+// or a `sort` (like `pct_upvoted:desc`), songs matching them - otherwise the latest songs
+async fn api(req: Request<State>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct ApiQuery {
+        q: Option<String>,
+        filter: Option<String>,
+        sort: Option<String>,
+    }
+    let ApiQuery { q, filter, sort } = req.query()?;
+    let query = search_query(q, filter, sort, API_LIMIT)?;
+
+    let conn = &db::establish_connection().map_err(db_error)?;
+    let results: Vec<(String, String, String)> = if query.text.trim().is_empty() && query.filters.is_empty() && query.sort.is_none() {
+        let results = db::latest_songs_with_data(conn, API_LIMIT as i64).map_err(db_error)?;
+        results.into_iter()
+            .map(|(key, hash, bsmeta)| {
+                let bsmeta: BeatSaverMap = serde_json::from_slice(&bsmeta).unwrap();
+                (num_to_key(key), hash, format!("{} {}", bsmeta.metadata.song_name, bsmeta.metadata.song_sub_name))
+            })
+            .collect()
+    } else {
+        let songs = search::search_songs(conn, &query)
+            .map_err(|e| tide::Error::from_str(StatusCode::ServiceUnavailable, format!("search failed: {:#}", e)))?;
+        songs.into_iter()
+            .map(|song| (song.key, song.hash, format!("{} {}", song.name, song.sub_name)))
+            .collect()
+    };
     Ok(Body::from_json(&results)?.into())
 }
 
-async fn submit(mut req: Request<()>) -> tide::Result {
+fn search_query(q: Option<String>, filter: Option<String>, sort: Option<String>, limit: usize) -> tide::Result<SearchQuery> {
+    let bad_request = |e: anyhow::Error| tide::Error::from_str(StatusCode::BadRequest, e.to_string());
+    let filters = filter.as_deref().map(Filter::parse_list).transpose().map_err(bad_request)?.unwrap_or_default();
+    let sort = sort.filter(|s| !s.trim().is_empty()).map(|s| s.parse::<Sort>()).transpose().map_err(bad_request)?;
+    let query = SearchQuery { text: q.unwrap_or_default(), filters, sort, limit };
+    let plugins = search::indexed_plugins()
+        .map_err(|e| tide::Error::from_str(StatusCode::InternalServerError, format!("{:#}", e)))?;
+    query.check_attributes(&search::search_attributes(&plugins)).map_err(bad_request)?;
+    Ok(query)
+}
+
+// Describes /api, including everything that can be filtered and sorted on
+async fn api_docs(_req: Request<State>) -> tide::Result {
+    let plugins = search::indexed_plugins()
+        .map_err(|e| tide::Error::from_str(StatusCode::InternalServerError, format!("{:#}", e)))?;
+    let attributes = search::search_attributes(&plugins);
+    let analyses: BTreeMap<_, _> = plugins.into_iter()
+        .map(|(name, info)| (name, json!({ "level": info.level, "outputs": info.outputs })))
+        .collect();
+    Ok(Body::from_json(&json!({
+        "endpoint": "/api",
+        "returns": "a list of [key, hash, name] for matching songs, or the latest songs if there are no parameters",
+        "parameters": {
+            "q": "text to search for in song names, descriptions, uploaders and modes",
+            "filter": "comma separated conditions that must all match, like `has_expertplus = true, song_duration < 180` - the operators are = != < <= > >=",
+            "sort": "an attribute to sort by, with an optional direction, like `pct_upvoted:desc`",
+        },
+        "filterable": attributes.filterable,
+        "sortable": attributes.sortable,
+        "analyses": {
+            "description": "analysis results are available as `<plugin>-<key>` attributes - beatmap level plugins also have \
+                `<plugin>-<characteristic>-<difficulty>-<key>` attributes for each beatmap, with the map attribute aggregated from them. \
+                Aggregate level plugins rank or compare songs against each other, and are periodically rerun over all songs. \
+                Bool and number results can be filtered on and numbers sorted on, arrays and objects are kept as they are",
+            "characteristics": dats::CHARACTERISTICS,
+            "difficulties": dats::DIFFICULTIES,
+            "plugins": analyses,
+        },
+    }))?.into())
+}
+
+// Clusters of likely duplicate songs from the last duplicates run - with `key`, just the cluster
+// containing that song (or none)
+async fn duplicates(req: Request<State>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct DuplicatesQuery {
+        key: Option<String>,
+    }
+    let DuplicatesQuery { key } = req.query()?;
+    let key = key
+        .map(|key| parse_key(&key).ok_or_else(|| tide::Error::from_str(StatusCode::BadRequest, format!("invalid key {:?}", key))))
+        .transpose()?;
+    let conn = &db::establish_connection().map_err(db_error)?;
+    let report = fingerprint::duplicate_report(conn, key).map_err(db_error)?;
+    Ok(Body::from_json(&report)?.into())
+}
+
+// How long /submit goes without sending an event before sending a `keepalive` one
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Queue an event for the browser, as (event name, JSON data). If the browser has gone the event
+// is dropped, and `submit` cancels the run.
+fn send_event(tx: &Sender<(&'static str, String)>, name: &'static str, data: &impl Serialize) {
+    let _ = tx.try_send((name, serde_json::to_string(data).expect("failed to serialize event")));
+}
+
+// Run a script (see `SubmitSource`) on a song, streaming server-sent events as it goes - a `log`
+// event for each line it logs, then a `result` or `error` event, with `keepalive` events while
+// it's quiet. All the event data is JSON. The run is on the plugin workers like /submit/batch,
+// and is cancelled if the browser goes away.
+async fn submit(mut req: Request<State>, sender: sse::Sender) -> tide::Result<()> {
     #[derive(Deserialize)]
     struct AnalysisSubmit {
         hash: String,
-        interp: String,
-        script: String,
+        #[serde(flatten)]
+        source: SubmitSource,
+    }
+    let AnalysisSubmit { hash, source } = req.body_json().await?;
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = channel::unbounded();
+    let run_cancel = cancel.clone();
+    let queued = queue_run(req.state(), Box::new(move |conn: &db::SqliteConnection| {
+        // Nobody is waiting for it any more
+        if run_cancel.load(Ordering::Relaxed) {
+            return
+        }
+        // Compiling is slow, so happens here rather than holding up the server
+        let plugin = match source.plugin() {
+            Ok(plugin) => plugin,
+            Err(e) => return send_event(&tx, "error", &format!("{:#}", e)),
+        };
+        let input = match plugin_input(conn, &hash) {
+            Ok(input) => input,
+            Err(e) => return send_event(&tx, "error", &format!("failed to load song: {}", e)),
+        };
+
+        // Whatever is after the last newline so far
+        let partial_line = Arc::new(Mutex::new(vec![]));
+        let log_sink: wasm::LogSink = {
+            let (tx, partial_line) = (tx.clone(), partial_line.clone());
+            Arc::new(move |buf: &[u8]| {
+                let mut partial_line = partial_line.lock().unwrap();
+                partial_line.extend_from_slice(buf);
+                while let Some(newline) = partial_line.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = partial_line.drain(..=newline).collect();
+                    send_event(&tx, "log", &String::from_utf8_lossy(&line[..newline]))
+                }
+            })
+        };
+        let ret = plugin.run_hooked(input, wasm::RunHooks { log_sink: Some(log_sink), cancel: Some(run_cancel) });
+        let rest = mem::take(&mut *partial_line.lock().unwrap());
+        if !rest.is_empty() {
+            send_event(&tx, "log", &String::from_utf8_lossy(&rest))
+        }
+        match ret {
+            Ok((_log, Ok(d))) => send_event(&tx, "result", &d),
+            Ok((_log, Err(e))) => send_event(&tx, "error", &format!("{:#}", e)),
+            Err(e) => send_event(&tx, "error", &format!("{:?}", e)),
+        }
+    })).await;
+    if let Err(e) = queued {
+        return Ok(sender.send("error", serde_json::to_string(&e.to_string())?, None).await?)
     }
-    let AnalysisSubmit { hash, interp, script } = req.body_json().await?;
+    let mut finished = false;
+    loop {
+        let (name, data) = match future::timeout(KEEPALIVE_INTERVAL, rx.recv()).await {
+            Ok(Ok(event)) => event,
+            Ok(Err(_)) => break,
+            // Sending something is the only way to notice the browser has gone while the run is
+            // still queued, or the plugin is quiet
+            Err(_) => ("keepalive", "null".to_owned()),
+        };
+        if name == "result" || name == "error" {
+            finished = true
+        }
+        if let Err(e) = sender.send(name, data, None).await {
+            cancel.store(true, Ordering::Relaxed);
+            return Err(e.into())
+        }
+    }
+    if !finished {
+        sender.send("error", serde_json::to_string("plugin run failed unexpectedly")?, None).await?;
+    }
+    Ok(())
+}
+
+fn plugin_input(conn: &db::SqliteConnection, hash: &str) -> Result<wasm::PluginInput, DbError> {
+    let dats = db::load_dats_for_analysis(conn, hash)?;
+    let bsmeta = db::get_bsmeta_by_hash(conn, hash)?;
+    let extra_meta = db::get_song_extra_meta(conn, hash)?;
+    Ok(wasm::PluginInput { hash: Some(hash.to_owned()), dats, bsmeta, extra_meta })
+}
+
+// The most songs a batch can run on
+const MAX_BATCH_SONGS: usize = 1000;
 
-    let (base_plugin, to_replace) = match interp.as_str() {
-        "js" => ("parity", "script.js"),
-        "py" => ("difficulty", "script.py"),
-        _ => return Ok(StatusCode::NotFound.into()),
+// Plugins for /submit and /submit/batch all run on these threads, so however many requests come in
+// only this many plugins run at once, and the rest wait their turn. Each run is also limited by the
+// plugin runtime, so can't hold a worker forever.
+const PLUGIN_WORKERS: usize = 4;
+// How many runs can be waiting for a worker before requests have to wait to queue more. A batch
+// only has PLUGIN_WORKERS runs queued at a time, so a /submit is never stuck behind a whole batch.
+const MAX_QUEUED_RUNS: usize = PLUGIN_WORKERS * 16;
+
+type PluginRun = Box<dyn FnOnce(&db::SqliteConnection) + Send>;
+
+#[derive(Clone)]
+struct State {
+    runs: Sender<PluginRun>,
+}
+
+fn start_plugin_workers(conn: db::SqliteConnection) -> Sender<PluginRun> {
+    let (tx, rx) = channel::bounded::<PluginRun>(MAX_QUEUED_RUNS);
+    for i in 0..PLUGIN_WORKERS {
+        let (rx, conn) = (rx.clone(), conn.clone());
+        thread::Builder::new()
+            .name(format!("plugin-worker-{}", i))
+            .spawn(move || {
+                while let Ok(run) = task::block_on(rx.recv()) {
+                    // A run that panics just never sends its result, which whoever queued it
+                    // notices when its channel closes
+                    let _ = panic::catch_unwind(AssertUnwindSafe(|| run(&conn)));
+                }
+            })
+            .expect("failed to start plugin worker");
+    }
+    tx
+}
+
+async fn queue_run(state: &State, run: PluginRun) -> tide::Result<()> {
+    state.runs.send(run).await
+        .map_err(|_| tide::Error::from_str(StatusCode::ServiceUnavailable, "plugin workers have stopped"))
+}
+
+// Cancels runs when the request that queued them goes away - a handler future is just dropped when
+// the client disconnects
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BatchSelection {
+    Keys(Vec<String>),
+    Search { q: Option<String>, filter: Option<String>, sort: Option<String> },
+    Latest(usize),
+}
+
+#[derive(Serialize)]
+struct BatchRow {
+    key: String,
+    hash: Option<String>,
+    result: Option<wasm::AnalysisResults>,
+    error: Option<String>,
+}
+
+// Run a script on many songs - picked by `keys`, a `search` like for /api, or the `latest` N -
+// returning a row for each with its result or why it failed. With `?format=csv` the results are
+// flattened into a column per output, like `export`.
+async fn submit_batch(mut req: Request<State>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct BatchSubmit {
+        #[serde(flatten)]
+        source: SubmitSource,
+        selection: BatchSelection,
+    }
+    #[derive(Deserialize)]
+    struct BatchQuery {
+        format: Option<String>,
+    }
+    let BatchQuery { format } = req.query()?;
+    let csv = match format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(f) => return Err(tide::Error::from_str(StatusCode::BadRequest, format!("unknown format {}", f))),
     };
-    let plugin_path = format!("plugins/dist/{}.tar", base_plugin);
-    let interp_path = format!("plugins/dist/{}.wasm", interp);
-    let base_tar_data = fs::read(&plugin_path).unwrap();
-    let mut tar_data = vec![];
-    {
-        let mut ar = tar::Builder::new(&mut tar_data);
-        for entry in tar::Archive::new(&*base_tar_data).entries().unwrap() {
-            println!("time for entry");
-            let entry = entry.unwrap();
-            let mut header = entry.header().to_owned();
-            println!("cloned header {:?}", entry.path());
-            if entry.path_bytes() == to_replace.as_bytes() {
-                println!("appending header manufactured");
-                header.set_size(script.as_bytes().len().try_into().unwrap());
-                header.set_cksum();
-                ar.append(&header, script.as_bytes())
-            } else {
-                println!("appending header");
-                ar.append(&header, entry)
-            }.unwrap();
-            print!("completed entry");
+    let BatchSubmit { source, selection } = req.body_json().await?;
+    // Compiling is slow, so is kept off the server's threads
+    let plugin = task::spawn_blocking(move || source.plugin()).await
+        .map_err(|e| tide::Error::from_str(StatusCode::BadRequest, format!("{:#}", e)))?;
+
+    let songs = task::spawn_blocking(move || -> tide::Result<_> {
+        let songs = selection.resolve()?;
+        if songs.len() > MAX_BATCH_SONGS {
+            let msg = format!("{} songs selected, more than the limit of {}", songs.len(), MAX_BATCH_SONGS);
+            return Err(tide::Error::from_str(StatusCode::BadRequest, msg))
         }
-        ar.finish().unwrap();
-    }
-    let plugin = super::wasm::dynamic_plugin("dynamic", interp_path.as_ref(), tar_data).unwrap();
-    let conn = &establish_connection();
-    let dats = load_dats_for_analysis(conn, &hash);
-    let ret = match plugin.run(dats) {
-        Ok((stderr, Ok(d))) => format!("success: {}\n\nstderr:{{#\n{}\n#}}", serde_json::to_string(&d).unwrap(), stderr),
-        Ok((stderr, Err(e))) => format!("error: {}\n\nstderr:{{#\n{}\n#}}", e, stderr),
-        Err(e) => format!("{:?}", e),
+        Ok(songs)
+    }).await?;
+    let state = req.state().clone();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let _cancel_on_drop = CancelOnDrop(cancel.clone());
+    let rows = run_batch(&state, plugin, songs, cancel).await?;
+
+    if csv {
+        let mut res: tide::Response = Body::from_bytes(batch_csv(&rows)?).into();
+        res.set_content_type("text/csv");
+        res.insert_header("Content-Disposition", "attachment; filename=\"batch.csv\"");
+        return Ok(res)
+    }
+    let failed = rows.iter().filter(|row| row.error.is_some()).count();
+    Ok(Body::from_json(&json!({
+        "succeeded": rows.len() - failed,
+        "failed": failed,
+        "rows": rows,
+    }))?.into())
+}
+
+impl BatchSelection {
+    // The (key, hash) of each song, with no hash if there's no such song
+    fn resolve(self) -> tide::Result<Vec<(String, Option<String>)>> {
+        if let BatchSelection::Keys(keys) = &self {
+            if keys.len() > MAX_BATCH_SONGS {
+                let msg = format!("{} songs selected, more than the limit of {}", keys.len(), MAX_BATCH_SONGS);
+                return Err(tide::Error::from_str(StatusCode::BadRequest, msg))
+            }
+            if let Some(key) = keys.iter().find(|key| parse_key(key).is_none()) {
+                return Err(tide::Error::from_str(StatusCode::BadRequest, format!("invalid key {:?}", key)))
+            }
+        }
+        let conn = &db::establish_connection().map_err(db_error)?;
+        Ok(match self {
+            BatchSelection::Keys(keys) => keys.into_iter()
+                .map(|key| {
+                    let meta = db::get_song_meta(conn, key_to_num(&key))?;
+                    Ok((key, meta.map(|meta| meta.hash)))
+                })
+                .collect::<Result<_, DbError>>().map_err(db_error)?,
+            // One more than allowed, so asking for too many is noticed
+            BatchSelection::Search { q, filter, sort } => search::search_songs(conn, &search_query(q, filter, sort, MAX_BATCH_SONGS + 1)?)
+                .map_err(|e| tide::Error::from_str(StatusCode::ServiceUnavailable, format!("search failed: {:#}", e)))?
+                .into_iter().map(|song| (song.key, Some(song.hash))).collect(),
+            BatchSelection::Latest(n) => {
+                let limit = cmp::min(n, MAX_BATCH_SONGS + 1);
+                db::latest_songs_with_data(conn, limit as i64).map_err(db_error)?
+                    .into_iter().map(|(key, hash, _)| (num_to_key(key), Some(hash))).collect()
+            },
+        })
+    }
+}
+
+// Each song is a separate run on the plugin workers, and the rows come back in the same order.
+// Only PLUGIN_WORKERS runs are queued at a time, the next as each finishes, so other requests get
+// their runs in between.
+async fn run_batch(state: &State, plugin: wasm::AnalysisPlugin, songs: Vec<(String, Option<String>)>, cancel: Arc<AtomicBool>) -> tide::Result<Vec<BatchRow>> {
+    let plugin = Arc::new(plugin);
+    let (tx, rx) = channel::unbounded();
+    let mut rows: Vec<Option<BatchRow>> = songs.iter().map(|_| None).collect();
+    let mut to_queue = songs.into_iter().enumerate();
+    let mut in_flight = 0;
+    loop {
+        while in_flight < PLUGIN_WORKERS {
+            let (i, (key, hash)) = match to_queue.next() {
+                Some(song) => song,
+                None => break,
+            };
+            let (plugin, tx, cancel) = (plugin.clone(), tx.clone(), cancel.clone());
+            queue_run(state, Box::new(move |conn: &db::SqliteConnection| {
+                // Caught here as well as by the worker, so there's always a row to send back
+                let res = panic::catch_unwind(AssertUnwindSafe(|| batch_result(conn, &plugin, hash.as_deref(), cancel)))
+                    .unwrap_or_else(|_| Err("plugin run failed unexpectedly".to_owned()));
+                let (result, error) = match res {
+                    Ok(result) => (Some(result), None),
+                    Err(e) => (None, Some(e)),
+                };
+                let _ = tx.try_send((i, BatchRow { key, hash, result, error }));
+            })).await?;
+            in_flight += 1;
+        }
+        if in_flight == 0 {
+            break
+        }
+        let (i, row) = rx.recv().await.expect("batch row channel closed");
+        rows[i] = Some(row);
+        in_flight -= 1;
+    }
+    Ok(rows.into_iter().map(|row| row.expect("batch run finished without a row")).collect())
+}
+
+fn batch_result(conn: &db::SqliteConnection, plugin: &wasm::AnalysisPlugin, hash: Option<&str>, cancel: Arc<AtomicBool>) -> Result<wasm::AnalysisResults, String> {
+    // Nobody is waiting for it any more
+    if cancel.load(Ordering::Relaxed) {
+        return Err("batch was cancelled".to_owned())
+    }
+    let hash = hash.ok_or_else(|| "no such song".to_owned())?;
+    let input = plugin_input(conn, hash).map_err(|e| format!("failed to load song: {}", e))?;
+    match plugin.run_hooked(input, wasm::RunHooks { log_sink: None, cancel: Some(cancel) }) {
+        Ok((_log, res)) => res.map_err(|e| format!("{:#}", e)),
+        Err(e) => Err(format!("{:?}", e)),
+    }
+}
+
+fn batch_csv(rows: &[BatchRow]) -> tide::Result<Vec<u8>> {
+    let outputs: BTreeSet<&String> = rows.iter()
+        .filter_map(|row| row.result.as_ref())
+        .flat_map(|result| result.map.keys())
+        .collect();
+    let mut writer = csv::Writer::from_writer(vec![]);
+    let header = ["key", "hash", "error"].iter().copied().chain(outputs.iter().map(|o| o.as_str()));
+    writer.write_record(header)?;
+    for row in rows {
+        let mut record = vec![
+            row.key.clone(),
+            row.hash.clone().unwrap_or_default(),
+            row.error.clone().unwrap_or_default(),
+        ];
+        for output in outputs.iter() {
+            let value = row.result.as_ref().and_then(|result| result.map.get(*output));
+            record.push(match value.map(serde_json::to_value).transpose()? {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(s)) => s,
+                Some(v) => v.to_string(),
+            })
+        }
+        writer.write_record(record)?;
+    }
+    Ok(writer.into_inner().map_err(|e| e.into_error())?)
+}
+
+// The biggest WASI module that can be submitted to run by itself
+const MAX_SUBMIT_WASM_BYTES: usize = 32 * 1024 * 1024;
+
+// What a submit runs - either a script for one of the interps from /api/interps, with any extra
+// files it needs alongside it in /work, or a WASI module that runs by itself. Extra files and
+// modules are base64. Like a registered plugin it runs once per song unless `level` is beatmap.
+#[derive(Deserialize)]
+struct SubmitSource {
+    interp: Option<String>,
+    script: Option<String>,
+    #[serde(default)]
+    files: BTreeMap<String, String>,
+    wasm: Option<String>,
+    #[serde(default)]
+    level: wasm::PluginLevel,
+}
+
+impl SubmitSource {
+    fn plugin(self) -> anyhow::Result<wasm::AnalysisPlugin> {
+        match self {
+            SubmitSource { interp: Some(interp), script: Some(script), files, wasm: None, level } => {
+                let files: BTreeMap<String, Vec<u8>> = files.into_iter()
+                    .map(|(path, data)| {
+                        let data = base64::decode(&data).with_context(|| format!("extra file {} isn't base64", path))?;
+                        Ok((path, data))
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                let tar_data = plugins::script_tar(&interp, &script, &files)?;
+                wasm::dynamic_plugin("dynamic", &plugins::interp_path(&interp), tar_data, level)
+            },
+            SubmitSource { interp: None, script: None, files, wasm: Some(module), level } if files.is_empty() => {
+                let module = base64::decode(&module).context("wasm isn't base64")?;
+                if module.len() > MAX_SUBMIT_WASM_BYTES {
+                    bail!("wasm is {} bytes, more than the limit of {}", module.len(), MAX_SUBMIT_WASM_BYTES)
+                }
+                wasm::wasm_plugin("dynamic", &module, level)
+            },
+            _ => bail!("submit either an interp and script (with optional files), or wasm by itself"),
+        }
+    }
+}
+
+// The interps scripts can be submitted for, and where in /work each runs its script from
+async fn interps(_req: Request<State>) -> tide::Result {
+    let interps = plugins::interp_list().map_err(|e| tide::Error::from_str(StatusCode::InternalServerError, format!("{:#}", e)))?;
+    Ok(Body::from_json(&interps)?.into())
+}
+
+// Save a script as a new version of a registered plugin, so `analyse` runs it on every song.
+// Only allowed with the token in PLUGIN_REGISTER_TOKEN, and not at all if that isn't set.
+async fn register_plugin(mut req: Request<State>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct PluginRegister {
+        name: String,
+        interp: String,
+        script: String,
+        #[serde(default)]
+        level: wasm::PluginLevel,
+        outputs: wasm::PluginSchema,
+    }
+    let token = env::var("PLUGIN_REGISTER_TOKEN").ok().filter(|token| !token.is_empty());
+    let authorized = match (token, req.header("Authorization")) {
+        (Some(token), Some(header)) => header.as_str() == format!("Bearer {}", token),
+        _ => false,
     };
-    Ok(ret.into())
+    if !authorized {
+        return Err(tide::Error::from_str(StatusCode::Forbidden, "registering plugins needs a valid token"))
+    }
+    let PluginRegister { name, interp, script, level, outputs } = req.body_json().await?;
+
+    let version = task::spawn_blocking({
+        let name = name.clone();
+        move || -> tide::Result<u32> {
+            let conn = db::establish_connection().map_err(db_error)?;
+            plugins::register_plugin(&conn, &name, &interp, &script, level, outputs)
+                .map_err(|e| tide::Error::from_str(StatusCode::BadRequest, format!("{:#}", e)))
+        }
+    }).await?;
+    Ok(Body::from_json(&json!({ "name": name, "version": version }))?.into())
 }
 
 pub fn serve() -> ! {
     task::block_on(async {
         //tide::log::with_level(tide::log::LevelFilter::Info);
-        let mut app = tide::new();
+        let conn = db::establish_connection().expect("failed to connect to database");
+        let mut app = tide::with_state(State { runs: start_plugin_workers(conn) });
         //app.at("/").get(index);
         app.at("/").serve_file("static/index.html").unwrap();
         app.at("/api").get(api);
-        app.at("/submit").post(submit);
+        app.at("/api/docs").get(api_docs);
+        app.at("/api/duplicates").get(duplicates);
+        app.at("/api/interps").get(interps);
+        app.at("/submit").post(sse::endpoint(submit));
+        app.at("/submit/batch").post(submit_batch);
+        app.at("/plugins").post(register_plugin);
         //app.at("/src").serve_dir("src/")?;
         //app.at("/example").serve_file("examples/static_file.html")?;
 